@@ -1,4 +1,4 @@
-use crate::Layer;
+use crate::{serialization::Serialization, Layer};
 use anyhow::*;
 use slas::prelude::*;
 use std::marker::PhantomData;
@@ -18,80 +18,184 @@ use std::marker::PhantomData;
 ///        |x| 1. / (x.powi(2) + 2. * x + 1.)
 /// }
 /// ```
+/// The derivative can also be expressed in terms of the output `y` of the function,
+/// which saves recomputing the function during backpropagation.
+/// ```
+/// use exotic::*;
+/// use slas::prelude::*;
+/// use std::marker::PhantomData;
+/// use anyhow::*;
+///
+/// activation!{[f32,f64]:
+///    Tanh =
+///        |x| x.tanh(),
+///        output |y| 1. - y * y
+/// }
+/// ```
+/// Hyperparameters are declared as fields with a default value, and can be used in both closures.
+/// The activation can then be created with either `default()` or `new`, which takes every field in order.
+/// With the generic `T:` form, the defaults are `f64` values that are converted with `T::from_f64`.
+/// ```
+/// use exotic::*;
+/// use slas::prelude::*;
+/// use std::marker::PhantomData;
+/// use anyhow::*;
+///
+/// activation!{[f32,f64]:
+///    LeakyRelu { alpha = 0.01 } =
+///        |x| if x > 0. { x } else { alpha * x },
+///        |x| if x > 0. { 1. } else { alpha }
+/// }
+///
+/// let _ = LeakyRelu::<f32, 4>::new(0.1);
+/// ```
+/// If the derivative is left out, it is computed with [`crate::dual::Dual`] numbers instead.
+/// The closure is then evaluated both on floats and on dual numbers, so it should only use methods that they share.
+/// ```
+/// use exotic::*;
+/// use slas::prelude::*;
+/// use std::marker::PhantomData;
+/// use anyhow::*;
+///
+/// activation!{[f32,f64]:
+///    Sigmoid =
+///        |x| x/(x + 1.)
+/// }
+/// ```
 #[macro_export]
 macro_rules! activation {
-    ([$($T: ty),*]: $name: ident = $f: expr, $d: expr $(,)?) => {
+    ([$($T: ty),*]: $name: ident $({$($fields: tt)*})? = $f: expr $(,)?) => {
+        $crate::activation!{@layer input [$($T),*] $name {$($($fields)*)?}, $f, |x| $crate::dual::derivative($f, x)}
+    };
+    (T: $name: ident $({$($fields: tt)*})? = $f: expr $(,)?) => {
+        $crate::activation!{@layer input T $name {$($($fields)*)?}, $f, |x| $crate::dual::derivative($f, x)}
+    };
+    ([$($T: ty),*]: $name: ident $({$($fields: tt)*})? = $f: expr, output $d: expr $(,)?) => {
+        $crate::activation!{@layer output [$($T),*] $name {$($($fields)*)?}, $f, $d}
+    };
+    (T: $name: ident $({$($fields: tt)*})? = $f: expr, output $d: expr $(,)?) => {
+        $crate::activation!{@layer output T $name {$($($fields)*)?}, $f, $d}
+    };
+    ([$($T: ty),*]: $name: ident $({$($fields: tt)*})? = $f: expr, $d: expr $(,)?) => {
+        $crate::activation!{@layer input [$($T),*] $name {$($($fields)*)?}, $f, $d}
+    };
+    (T: $name: ident $({$($fields: tt)*})? = $f: expr, $d: expr $(,)?) => {
+        $crate::activation!{@layer input T $name {$($($fields)*)?}, $f, $d}
+    };
+
+    (@layer $arg: ident [$($T: ty),*] $name: ident $fields: tt, $f: expr, $d: expr) => {
+        $crate::activation!{@struct $name $fields}
+        $($crate::activation!{@impl $arg [] $T, $name $fields, $f, $d})*
+    };
+    (@layer $arg: ident T $name: ident $fields: tt, $f: expr, $d: expr) => {
+        $crate::activation!{@struct $name $fields}
+        $crate::activation!{@impl $arg [T: Float,] T, $name $fields, $f, $d}
+    };
+
+    (@struct $name: ident {}) => {
         #[derive(Clone, Copy, Default)]
         pub struct $name<T: Float, const LEN: usize>(pub PhantomData<T>);
 
-        $(
-        impl<const LEN: usize> Layer<$T, LEN, LEN, LEN> for $name<$T, LEN> {
+        impl<T: Float, const LEN: usize> $crate::parameters::Parameters<T> for $name<T, LEN> {}
+    };
+    (@struct $name: ident {$($field: ident = $default: expr),+ $(,)?}) => {
+        #[derive(Clone, Copy)]
+        pub struct $name<T: Float, const LEN: usize> {
+            $(pub $field: T),+
+        }
+
+        impl<T: Float, const LEN: usize> $name<T, LEN> {
+            pub fn new($($field: T),+) -> Self {
+                Self { $($field),+ }
+            }
+        }
+
+        // Hyperparameters aren't trained, so they aren't parameters.
+        impl<T: Float, const LEN: usize> $crate::parameters::Parameters<T> for $name<T, LEN> {}
+    };
+
+    (@default [$($gen: tt)*] $T: ty, $name: ident {}) => {};
+    // In the generic form the defaults are `f64` literals, which have to be converted to `T`.
+    (@default [T: Float,] $T: ty, $name: ident {$($field: ident = $default: expr),+}) => {
+        impl<T: Float, const LEN: usize> Default for $name<T, LEN> {
+            fn default() -> Self {
+                Self { $($field: T::from_f64($default)),+ }
+            }
+        }
+    };
+    (@default [$($gen: tt)*] $T: ty, $name: ident {$($field: ident = $default: expr),+}) => {
+        impl<$($gen)* const LEN: usize> Default for $name<$T, LEN> {
+            fn default() -> Self {
+                Self { $($field: $default),+ }
+            }
+        }
+    };
+
+    // Selects what the derivative is a function of.
+    (@arg input, $x: expr, $y: expr) => {
+        $x
+    };
+    (@arg output, $x: expr, $y: expr) => {
+        $y
+    };
+
+    // Hyperparameters are available to the closures as local variables.
+    (@impl $arg: ident [$($gen: tt)*] $T: ty, $name: ident {$($field: ident = $default: expr),* $(,)?}, $f: expr, $d: expr) => {
+        $crate::activation!{@default [$($gen)*] $T, $name {$($field = $default),*}}
+
+        impl<$($gen)* const LEN: usize> Layer<$T, LEN, LEN, LEN> for $name<$T, LEN> {
             type Gradient = [$T; LEN];
 
+            #[allow(unused_variables)]
             fn predict(&mut self, i: impl StaticVec<$T, LEN>, buffer: &mut impl StaticVec<$T, LEN>) -> Result<()> {
+                $(let $field = self.$field;)*
                 let buffer = buffer.mut_moo_ref();
-                let fun: fn($T)->$T = $f;
+                let fun = $crate::activation::typed::<$T, _>($f);
                 for n in 0..LEN {
                     buffer[n] = fun(i.moo_ref()[n])
                 }
                 Ok(())
             }
 
-            /// Here buffer is shadowed, so a NullVec can safely be passed.
+            /// Here buffer must hold the output of `predict`, as the derivative may be computed from it.
+            #[allow(unused_variables)]
             fn backpropagate(
                 &mut self,
                 i: impl StaticVec<$T, LEN>,
-                _buffer: &impl StaticVec<$T, LEN>,
+                o: &impl StaticVec<$T, LEN>,
                 gradient: impl StaticVec<$T, LEN>,
             ) -> Result<[$T; LEN]> {
-                let fun: fn($T)->$T = $d;
+                $(let $field = self.$field;)*
+                let fun = $crate::activation::typed::<$T, _>($d);
                 let mut buffer = [num!(0); LEN];
                 for n in 0..LEN {
-                    buffer[n] = fun(i.moo_ref()[n]) * gradient.moo_ref()[n]
+                    buffer[n] = fun($crate::activation!(@arg $arg, i.moo_ref()[n], o.moo_ref()[n])) * gradient.moo_ref()[n]
                 }
                 Ok(buffer)
             }
         }
 
-        //impl<const LEN: usize> Serialization<0> for $name<$T, LEN> {
-        //    fn serialize_into(&self, _: &mut [[u8; FLOAT_BYTES]]) {}
-        //    fn deserialize(&mut self, _: &mut dyn std::iter::Iterator<Item = u8>) {}
-        //}
-    )*};
-    (T: $name: ident = $f: expr, $d: expr $(,)?) => {
-        #[derive(Clone, Copy, Default)]
-        pub struct $name<T: Float, const LEN: usize>(pub PhantomData<T>);
-
-        impl<T: Float, const LEN: usize> Layer<T, LEN, LEN, LEN> for $name<T, LEN> {
-            type Gradient = [T; LEN];
+        impl<$($gen)* const LEN: usize> $crate::SampleLayer<$T, LEN, LEN> for $name<$T, LEN> {}
+        $crate::sample_batch_layer!([$($gen)* const LEN: usize,] $T, LEN, LEN, $name<$T, LEN>);
 
-            fn predict(&mut self, i: impl StaticVec<T, LEN>, buffer: &mut impl StaticVec<T, LEN>) -> Result<()> {
-                let buffer = buffer.mut_moo_ref();
-                let fun: fn(T)->T = $f;
-                for n in 0..LEN {
-                    buffer[n] = fun(i.moo_ref()[n])
-                }
+        impl<$($gen)* const LEN: usize> $crate::serialization::Serialization for $name<$T, LEN> {
+            fn serialize_into(&self, _: &mut dyn std::io::Write) -> Result<()> {
                 Ok(())
             }
-
-            /// Here buffer is shadowed, so a NullVec can safely be passed.
-            fn backpropagate(
-                &mut self,
-                i: impl StaticVec<T, LEN>,
-                _buffer: &impl StaticVec<T, LEN>,
-                gradient: impl StaticVec<T, LEN>,
-            ) -> Result<[T; LEN]> {
-                let fun: fn(T)->T = $d;
-                let mut buffer = [num!(0); LEN];
-                for n in 0..LEN {
-                    buffer[n] = fun(i.moo_ref()[n]) * gradient.moo_ref()[n]
-                }
-                Ok(buffer)
+            fn deserialize_from(&mut self, _: &mut dyn std::io::Read) -> Result<()> {
+                Ok(())
             }
         }
     };
 }
 
+/// Used by `activation!` to give closures a type, without coercing them to function pointers,
+/// so they can use the hyperparameters of the activation.
+#[doc(hidden)]
+pub fn typed<T, F: Fn(T) -> T>(f: F) -> F {
+    f
+}
+
 pub fn sigmoid<T: Float>(x: T) -> T {
     T::_1 / (T::_1 + (-x).exp_())
 }
@@ -99,13 +203,13 @@ pub fn sigmoid<T: Float>(x: T) -> T {
 activation! {
 T: Sigmoid =
         |x| sigmoid(x),
-        |x| sigmoid(T::_1 - x),
+        output |y| y * (T::_1 - y),
 }
 
 activation! {
 [f32, f64]: Tanh =
         |x| x.tanh(),
-        |x| (-x.tanh().powi(2)) + 1.,
+        output |y| 1. - y * y,
 }
 
 // x \cdot \sigma(x)
@@ -137,35 +241,226 @@ T: Square =
         |x| x * T::_2,
 }
 
+activation! {
+[f32, f64]: LeakyRelu { alpha = 0.01 } =
+        |x| if x > 0. { x } else { alpha * x },
+        |x| if x > 0. { 1. } else { alpha },
+}
+
+activation! {
+[f32, f64]: Elu { alpha = 1. } =
+        |x| if x > 0. { x } else { alpha * x.exp_m1() },
+        |x| if x > 0. { 1. } else { alpha * x.exp() },
+}
+
+activation! {
+[f32, f64]: Selu { alpha = 1.673_263_2, scale = 1.050_701 } =
+        |x| scale * if x > 0. { x } else { alpha * x.exp_m1() },
+        |x| scale * if x > 0. { 1. } else { alpha * x.exp() },
+}
+
+/// Error function, using the approximation from Abramowitz and Stegun (7.1.26), which has a maximum error of 1.5e-7.
+pub fn erf<T: Float + PartialOrd>(x: T) -> T {
+    let sign = if x < T::_0 { -T::_1 } else { T::_1 };
+    let x = if x < T::_0 { -x } else { x };
+
+    let [p, a1, a2, a3, a4, a5] = [
+        0.3275911,
+        0.254829592,
+        -0.284496736,
+        1.421413741,
+        -1.453152027,
+        1.061405429,
+    ]
+    .map(T::from_f64);
+    let t = T::_1 / (T::_1 + p * x);
+    let poly = t * (a1 + t * (a2 + t * (a3 + t * (a4 + t * a5))));
+    sign * (T::_1 - poly * (-x * x).exp_())
+}
+
+/// Cumulative distribution function of the standard normal distribution.
+pub fn normal_cdf<T: Float + PartialOrd>(x: T) -> T {
+    (T::_1 + erf(x * T::from_f64(std::f64::consts::FRAC_1_SQRT_2))) / T::_2
+}
+
+/// Probability density function of the standard normal distribution.
+pub fn normal_pdf<T: Float>(x: T) -> T {
+    let scale = std::f64::consts::FRAC_2_SQRT_PI * std::f64::consts::FRAC_1_SQRT_2 / 2.;
+    (-x * x / T::_2).exp_() * T::from_f64(scale)
+}
+
+// x \cdot \Phi(x)
+// \Phi(x) + x \cdot \phi(x)
+activation! {
+[f32, f64]: Gelu =
+        |x| x * normal_cdf(x),
+        |x| normal_cdf(x) + x * normal_pdf(x),
+}
+
+activation! {
+[f32, f64]: GeluTanh =
+        |x| 0.5 * x * (1. + (0.797_884_6 * (x + 0.044715 * x * x * x)).tanh()),
+}
+
+activation! {
+[f32, f64]: Softplus =
+        |x| x.max(0.) + (-x.abs()).exp().ln_1p(),
+        |x| 1. / (1. + (-x).exp()),
+}
+
+// x \cdot \tanh(\mathrm{softplus}(x))
+activation! {
+[f32, f64]: Mish =
+        |x| x * (x.max(0.) + (1. + (-x.abs()).exp()).ln()).tanh(),
+}
+
+activation! {
+[f32, f64]: HardSigmoid =
+        |x| ((x + 3.) / 6.).clamp(0., 1.),
+        |x| if x > -3. && x < 3. { 1. / 6. } else { 0. },
+}
+
+activation! {
+[f32, f64]: HardSwish =
+        |x| x * ((x + 3.) / 6.).clamp(0., 1.),
+        |x| if x <= -3. {
+            0.
+        } else if x >= 3. {
+            1.
+        } else {
+            (2. * x + 3.) / 6.
+        },
+}
+
+activation! {
+[f32, f64]: Softsign =
+        |x| x / (1. + x.abs()),
+        output |y| (1. - y.abs()).powi(2),
+}
+
+/// Numerically stable softmax, computed by subtracting the largest input before exponentiating.
+fn softmax<T: Float + PartialOrd + std::iter::Sum, const LEN: usize>(
+    i: &[T; LEN],
+    buffer: &mut [T; LEN],
+) {
+    let max = i.iter().fold(i[0], |max, &x| if x > max { x } else { max });
+    for (o, &x) in buffer.iter_mut().zip(i.iter()) {
+        *o = (x - max).exp_();
+    }
+    let sum: T = buffer.iter().copied().sum();
+    for o in buffer.iter_mut() {
+        *o = *o / sum;
+    }
+}
+
 #[derive(Clone, Copy, Default)]
 pub struct Softmax<T: Float, const LEN: usize>(pub PhantomData<T>);
 
-impl<T: Float + std::iter::Sum, const LEN: usize> Layer<T, LEN, LEN, LEN> for Softmax<T, LEN> {
+impl<T: Float + PartialOrd + std::iter::Sum, const LEN: usize> Layer<T, LEN, LEN, LEN>
+    for Softmax<T, LEN>
+{
     type Gradient = [T; LEN];
     fn predict(
         &mut self,
         i: impl StaticVec<T, LEN>,
         buffer: &mut impl StaticVec<T, LEN>,
     ) -> Result<()> {
-        let buffer = buffer.mut_moo_ref();
-        let sum: T = (0..LEN).map(|n| i.moo_ref()[n].exp_()).sum();
-        for n in 0..LEN {
-            buffer[n] = i.moo_ref()[n].exp_() / sum
-        }
+        softmax(i.moo_ref(), buffer.mut_moo_ref());
         Ok(())
     }
+
+    /// The product of the full softmax Jacobian and the gradient, `s_i * (g_i - sum_j s_j * g_j)`.
+    /// Here buffer must hold the output of `predict`, which is `s`.
     fn backpropagate(
         &mut self,
-        i: impl StaticVec<T, LEN>,
-        _buffer: &impl StaticVec<T, LEN>,
+        _i: impl StaticVec<T, LEN>,
+        o: &impl StaticVec<T, LEN>,
         gradient: impl StaticVec<T, LEN>,
     ) -> Result<[T; LEN]> {
-        let mut buffer = [num!(0); LEN];
-        let sum: T = (0..LEN).map(|n| i.moo_ref()[n].exp_()).sum();
-        for n in 0..LEN {
-            buffer[n] = i.moo_ref()[n].exp_() / sum;
-            buffer[n] = buffer[n] * (T::_1 - buffer[n]) * gradient.moo_ref()[n]
+        let mut buffer = *o.moo_ref();
+
+        let gradient = gradient.moo_ref();
+        let dot: T = buffer
+            .iter()
+            .zip(gradient.iter())
+            .map(|(&s, &g)| s * g)
+            .sum();
+        for (s, &g) in buffer.iter_mut().zip(gradient.iter()) {
+            *s = *s * (g - dot)
         }
         Ok(buffer)
     }
 }
+
+/// Logarithm of the softmax, which is more stable than taking the logarithm of the output of `Softmax`.
+#[derive(Clone, Copy, Default)]
+pub struct LogSoftmax<T: Float, const LEN: usize>(pub PhantomData<T>);
+
+/// `Float` has no logarithm, so this is implemented for `f32` and `f64`, like the `[f32, f64]` activations.
+macro_rules! impl_log_softmax {
+    ($($T: ty),*) => {$(
+        impl<const LEN: usize> Layer<$T, LEN, LEN, LEN> for LogSoftmax<$T, LEN> {
+            type Gradient = [$T; LEN];
+            fn predict(
+                &mut self,
+                i: impl StaticVec<$T, LEN>,
+                buffer: &mut impl StaticVec<$T, LEN>,
+            ) -> Result<()> {
+                let i = i.moo_ref();
+                let max = i.iter().fold(i[0], |max, &x| x.max(max));
+                let log_sum = max + i.iter().map(|&x| (x - max).exp()).sum::<$T>().ln();
+                for (o, &x) in buffer.mut_moo_ref().iter_mut().zip(i.iter()) {
+                    *o = x - log_sum
+                }
+                Ok(())
+            }
+
+            /// `g_i - softmax_i * sum_j g_j`.
+            /// Here buffer must hold the output of `predict`, which is the logarithm of the softmax.
+            fn backpropagate(
+                &mut self,
+                _i: impl StaticVec<$T, LEN>,
+                o: &impl StaticVec<$T, LEN>,
+                gradient: impl StaticVec<$T, LEN>,
+            ) -> Result<[$T; LEN]> {
+                let gradient = gradient.moo_ref();
+                let sum: $T = gradient.iter().sum();
+                let mut buffer = [0.; LEN];
+                for n in 0..LEN {
+                    buffer[n] = gradient[n] - o.moo_ref()[n].exp() * sum
+                }
+                Ok(buffer)
+            }
+        }
+    )*};
+}
+
+impl_log_softmax!(f32, f64);
+
+/// Batched, serialization and parameter impls for the softmax layers, which have no parameters.
+macro_rules! impl_softmax {
+    ($($name: ident),*) => {$(
+        impl<T: Float, const LEN: usize> crate::SampleLayer<T, LEN, LEN> for $name<T, LEN>
+        where
+            Self: Layer<T, LEN, LEN, LEN, Gradient = [T; LEN]>,
+        {
+        }
+        crate::sample_batch_layer!(
+            [T: Float, const LEN: usize,] T, LEN, LEN, $name<T, LEN>
+            where Self: Layer<T, LEN, LEN, LEN, Gradient = [T; LEN]>
+        );
+
+        impl<T: Float, const LEN: usize> Serialization for $name<T, LEN> {
+            fn serialize_into(&self, _: &mut dyn std::io::Write) -> Result<()> {
+                Ok(())
+            }
+            fn deserialize_from(&mut self, _: &mut dyn std::io::Read) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<T: Float, const LEN: usize> crate::parameters::Parameters<T> for $name<T, LEN> {}
+    )*};
+}
+
+impl_softmax!(Softmax, LogSoftmax);
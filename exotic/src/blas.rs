@@ -0,0 +1,126 @@
+//! Safe wrappers around the BLAS routines used directly by layers.
+//! These are called through cblas, which is the same binding the slas Blas backend uses.
+
+use cblas_sys::{CBLAS_LAYOUT::CblasRowMajor, CBLAS_TRANSPOSE, CBLAS_TRANSPOSE::*};
+
+fn transpose(t: bool) -> CBLAS_TRANSPOSE {
+    if t {
+        CblasTrans
+    } else {
+        CblasNoTrans
+    }
+}
+
+pub(crate) trait BlasFloat: Sized {
+    /// Row-major `c = alpha * op(a) * op(b) + beta * c`,
+    /// where `op(a)` is `m` by `k`, `op(b)` is `k` by `n`, and `op` transposes its argument if `trans_*` is set.
+    #[allow(clippy::too_many_arguments)]
+    fn gemm(
+        trans_a: bool,
+        trans_b: bool,
+        m: usize,
+        n: usize,
+        k: usize,
+        alpha: Self,
+        a: &[Self],
+        b: &[Self],
+        beta: Self,
+        c: &mut [Self],
+    );
+
+    /// Row-major `y = alpha * op(a) * x + beta * y`, where `a` is `m` by `n`.
+    #[allow(clippy::too_many_arguments)]
+    fn gemv(
+        trans: bool,
+        m: usize,
+        n: usize,
+        alpha: Self,
+        a: &[Self],
+        x: &[Self],
+        beta: Self,
+        y: &mut [Self],
+    );
+}
+
+macro_rules! impl_blas_float {
+    ($T: ty, $gemm: ident, $gemv: ident) => {
+        impl BlasFloat for $T {
+            fn gemm(
+                trans_a: bool,
+                trans_b: bool,
+                m: usize,
+                n: usize,
+                k: usize,
+                alpha: Self,
+                a: &[Self],
+                b: &[Self],
+                beta: Self,
+                c: &mut [Self],
+            ) {
+                assert!(a.len() >= m * k && b.len() >= k * n && c.len() >= m * n);
+                let lda = if trans_a { m } else { k };
+                let ldb = if trans_b { k } else { n };
+                unsafe {
+                    cblas_sys::$gemm(
+                        CblasRowMajor,
+                        transpose(trans_a),
+                        transpose(trans_b),
+                        m as i32,
+                        n as i32,
+                        k as i32,
+                        alpha,
+                        a.as_ptr(),
+                        lda as i32,
+                        b.as_ptr(),
+                        ldb as i32,
+                        beta,
+                        c.as_mut_ptr(),
+                        n as i32,
+                    )
+                }
+            }
+
+            fn gemv(
+                trans: bool,
+                m: usize,
+                n: usize,
+                alpha: Self,
+                a: &[Self],
+                x: &[Self],
+                beta: Self,
+                y: &mut [Self],
+            ) {
+                let (x_len, y_len) = if trans { (m, n) } else { (n, m) };
+                assert!(a.len() >= m * n && x.len() >= x_len && y.len() >= y_len);
+                unsafe {
+                    cblas_sys::$gemv(
+                        CblasRowMajor,
+                        transpose(trans),
+                        m as i32,
+                        n as i32,
+                        alpha,
+                        a.as_ptr(),
+                        n as i32,
+                        x.as_ptr(),
+                        1,
+                        beta,
+                        y.as_mut_ptr(),
+                        1,
+                    )
+                }
+            }
+        }
+    };
+}
+
+impl_blas_float!(f32, cblas_sgemm, cblas_sgemv);
+impl_blas_float!(f64, cblas_dgemm, cblas_dgemv);
+
+/// View a batch of vectors as one contiguous row-major matrix.
+pub(crate) fn flatten<T, const LEN: usize>(batch: &[[T; LEN]]) -> &[T] {
+    unsafe { std::slice::from_raw_parts(batch.as_ptr() as *const T, batch.len() * LEN) }
+}
+
+pub(crate) fn flatten_mut<T, const LEN: usize>(batch: &mut [[T; LEN]]) -> &mut [T] {
+    unsafe { std::slice::from_raw_parts_mut(batch.as_mut_ptr() as *mut T, batch.len() * LEN) }
+}
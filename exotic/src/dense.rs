@@ -1,73 +1,216 @@
-use crate::*;
+use crate::{
+    blas::*,
+    optimizer::*,
+    param::{impl_training, Param},
+    parameters::Parameters,
+    serialization::Serialization,
+    transpose::Transpose,
+    *,
+};
 use slas::backends::operations::MatrixMul;
 
 #[derive(Clone, Copy)]
-pub struct DenseLayer<T: Float, B: Backend<T>, const I_LEN: usize, const O_LEN: usize>
-where
+pub struct DenseLayer<
+    T: Float,
+    B: Backend<T>,
+    const I_LEN: usize,
+    const O_LEN: usize,
+    Opt: Optimizer<T> = Sgd<T>,
+> where
     [(); O_LEN * I_LEN]:,
 {
-    pub weights: [T; O_LEN * I_LEN],
-    pub biasies: [T; O_LEN],
-    pub lr: T,
+    pub weights: Param<[T; O_LEN * I_LEN], [Opt::State; O_LEN * I_LEN]>,
+    pub biasies: Param<[T; O_LEN], [Opt::State; O_LEN]>,
+    pub optimizer: Opt,
+    accumulate: bool,
+    frozen: bool,
     backend: B,
 }
 
-impl<T: Float + Display, B: Backend<T>, const I_LEN: usize, const O_LEN: usize>
-    DenseLayer<T, B, I_LEN, O_LEN>
+impl<
+        T: Float + Display,
+        B: Backend<T>,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        Opt: Optimizer<T>,
+    > DenseLayer<T, B, I_LEN, O_LEN, Opt>
 where
     [(); O_LEN * I_LEN]:,
 {
-    pub fn random(lr: T) -> Self {
+    pub fn random(optimizer: Opt) -> Self {
         let xavier = || -> T {
             (random::<T>() - num!(0.5)) * (T::_2 / (T::from_f64((O_LEN + I_LEN) as f64)))
         };
 
-        let mut buffer: Self = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-        buffer.backend = B::default();
-        buffer.weights.iter_mut().for_each(|n| *n = xavier());
-        buffer.biasies.iter_mut().for_each(|n| *n = xavier());
-        buffer.lr = lr;
-        buffer
+        let mut weights = [T::_0; O_LEN * I_LEN];
+        let mut biasies = [T::_0; O_LEN];
+        weights.iter_mut().for_each(|n| *n = xavier());
+        biasies.iter_mut().for_each(|n| *n = xavier());
+        Self {
+            weights: Param {
+                value: weights,
+                gradient: [T::_0; O_LEN * I_LEN],
+                state: [Opt::State::default(); O_LEN * I_LEN],
+            },
+            biasies: Param {
+                value: biasies,
+                gradient: [T::_0; O_LEN],
+                state: [Opt::State::default(); O_LEN],
+            },
+            optimizer,
+            accumulate: false,
+            frozen: false,
+            backend: B::default(),
+        }
+    }
+
+    /// Add the accumulated gradients of another copy of the layer to the gradients of this one.
+    /// Useful for reducing the gradients of data-parallel replicas, before calling `apply_gradients`.
+    pub fn add_gradients(&mut self, other: &Self) {
+        self.weights.add_gradients(&other.weights);
+        self.biasies.add_gradients(&other.biasies);
     }
 }
 
 #[derive(Clone)]
-pub struct DenseHeapLayer<T: Float, B: Backend<T>, const I_LEN: usize, const O_LEN: usize> {
-    pub weights: Vec<T>,
-    pub biasies: Vec<T>,
-    pub lr: T,
+pub struct DenseHeapLayer<
+    T: Float,
+    B: Backend<T>,
+    const I_LEN: usize,
+    const O_LEN: usize,
+    Opt: Optimizer<T> = Sgd<T>,
+> {
+    pub weights: Param<Vec<T>, Vec<Opt::State>>,
+    pub biasies: Param<Vec<T>, Vec<Opt::State>>,
+    pub optimizer: Opt,
+    accumulate: bool,
+    frozen: bool,
     backend: B,
 }
 
-impl<T: Float + Display, B: Backend<T>, const I_LEN: usize, const O_LEN: usize>
-    DenseHeapLayer<T, B, I_LEN, O_LEN>
+impl<
+        T: Float + Display,
+        B: Backend<T>,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        Opt: Optimizer<T>,
+    > DenseHeapLayer<T, B, I_LEN, O_LEN, Opt>
 where
     [(); O_LEN * I_LEN]:,
 {
-    pub fn random(lr: T) -> Self {
+    pub fn random(optimizer: Opt) -> Self {
         let xavier = || -> T {
             (random::<T>() - num!(0.5)) * (T::_2 / (T::from_f64((O_LEN + I_LEN) as f64)))
         };
 
         let backend = B::default();
-        let lr = lr;
         let mut weights = vec![T::_0; O_LEN * I_LEN];
         let mut biasies = vec![T::_0; O_LEN];
         weights.iter_mut().for_each(|n| *n = xavier());
         biasies.iter_mut().for_each(|n| *n = xavier());
         Self {
-            weights,
-            biasies,
-            lr,
+            weights: Param {
+                value: weights,
+                gradient: vec![T::_0; O_LEN * I_LEN],
+                state: vec![Opt::State::default(); O_LEN * I_LEN],
+            },
+            biasies: Param {
+                value: biasies,
+                gradient: vec![T::_0; O_LEN],
+                state: vec![Opt::State::default(); O_LEN],
+            },
+            optimizer,
+            accumulate: false,
+            frozen: false,
             backend,
         }
     }
+
+    /// Add the accumulated gradients of another copy of the layer to the gradients of this one.
+    /// Useful for reducing the gradients of data-parallel replicas, before calling `apply_gradients`.
+    pub fn add_gradients(&mut self, other: &Self) {
+        self.weights.add_gradients(&other.weights);
+        self.biasies.add_gradients(&other.biasies);
+    }
+}
+
+macro_rules! impl_dense_serialization {
+    ($layer_ty: ident) => {
+        impl<
+                T: Float + Serialization,
+                B: Backend<T>,
+                const I_LEN: usize,
+                const O_LEN: usize,
+                Opt: Optimizer<T>,
+            > Serialization for $layer_ty<T, B, I_LEN, O_LEN, Opt>
+        where
+            [(); O_LEN * I_LEN]:,
+        {
+            fn serialize_into(&self, writer: &mut dyn std::io::Write) -> Result<()> {
+                self.weights.value.serialize_into(writer)?;
+                self.biasies.value.serialize_into(writer)?;
+                self.weights.state.serialize_into(writer)?;
+                self.biasies.state.serialize_into(writer)?;
+                self.optimizer.serialize_into(writer)
+            }
+
+            fn deserialize_from(&mut self, reader: &mut dyn std::io::Read) -> Result<()> {
+                self.weights.value.deserialize_from(reader)?;
+                self.biasies.value.deserialize_from(reader)?;
+                self.weights.state.deserialize_from(reader)?;
+                self.biasies.state.deserialize_from(reader)?;
+                self.optimizer.deserialize_from(reader)
+            }
+        }
+    };
 }
 
+impl_dense_serialization!(DenseLayer);
+impl_dense_serialization!(DenseHeapLayer);
+
+macro_rules! impl_dense_parameters {
+    ($layer_ty: ident) => {
+        impl<
+                T: Float,
+                B: Backend<T>,
+                const I_LEN: usize,
+                const O_LEN: usize,
+                Opt: Optimizer<T>,
+            > Parameters<T> for $layer_ty<T, B, I_LEN, O_LEN, Opt>
+        where
+            [(); O_LEN * I_LEN]:,
+        {
+            const PARAM_COUNT: usize = O_LEN * I_LEN + O_LEN;
+
+            fn visit_params(&self, visitor: &mut impl FnMut(&str, &[T])) {
+                visitor("weights", &self.weights.value);
+                visitor("biasies", &self.biasies.value);
+            }
+
+            fn visit_params_mut(&mut self, visitor: &mut impl FnMut(&str, &mut [T])) {
+                visitor("weights", &mut self.weights.value);
+                visitor("biasies", &mut self.biasies.value);
+            }
+
+            fn visit_grads(&self, visitor: &mut impl FnMut(&str, &[T])) {
+                visitor("weights", &self.weights.gradient);
+                visitor("biasies", &self.biasies.gradient);
+            }
+        }
+    };
+}
+
+impl_dense_parameters!(DenseLayer);
+impl_dense_parameters!(DenseHeapLayer);
+
 macro_rules! impl_dense {
     ($T:ty: $layer_ty: ident $($w_len: expr)?) => {
-        impl<B: Backend<$T> + MatrixMul<$T>, const I_LEN: usize, const O_LEN: usize>
-            Layer<$T, I_LEN, O_LEN, O_LEN> for $layer_ty<$T, B, I_LEN, O_LEN>
+        impl<
+                B: Backend<$T> + MatrixMul<$T>,
+                const I_LEN: usize,
+                const O_LEN: usize,
+                Opt: Optimizer<$T>,
+            > Layer<$T, I_LEN, O_LEN, O_LEN> for $layer_ty<$T, B, I_LEN, O_LEN, Opt>
         where
             [(); O_LEN * I_LEN]:,
         {
@@ -79,11 +222,12 @@ macro_rules! impl_dense {
                 buffer: &mut impl StaticVec<$T, O_LEN>,
             ) -> Result<()> {
                 self.weights
+                    .value
                     .moo_ref::<$($w_len)?>()
                     .matrix_ref::<B, O_LEN, I_LEN>()
                     .vector_mul(i.moo_ref())
                     .moo_ref()
-                    .add_into(self.biasies.moo_ref(), buffer.mut_moo_ref());
+                    .add_into(self.biasies.value.moo_ref(), buffer.mut_moo_ref());
                 Ok(())
             }
 
@@ -97,22 +241,173 @@ macro_rules! impl_dense {
                 let mut buffer = [num!(0); I_LEN];
                 let gradient = gradient.moo_ref();
                 let input = i.moo_ref();
-                let mut weights = self
-                    .weights
-                    .mut_moo_ref::<$($w_len)?>()
-                    .matrix_mut_ref::<B, O_LEN, I_LEN>();
 
-                //let weights = weights.as_transposed_mut();
+                // The input gradient must be computed from the weights used in the forward pass,
+                // so this has to happen before the update.
+                <$T>::gemv(true, O_LEN, I_LEN, 1., &self.weights.value, gradient, 0., &mut buffer);
+
+                if self.frozen {
+                    return Ok(buffer);
+                }
+
+                for j in 0..O_LEN {
+                    self.biasies.gradient[j] += gradient[j];
+
+                    for i in 0..I_LEN {
+                        self.weights.gradient[j * I_LEN + i] += gradient[j] * input[i];
+                    }
+                }
+
+                if !self.accumulate {
+                    self.apply_gradients();
+                }
+
+                Ok(buffer)
+            }
+
+            impl_training!($T; weights, biasies);
+        }
+
+        impl<
+                B: Backend<$T> + MatrixMul<$T>,
+                const I_LEN: usize,
+                const O_LEN: usize,
+                const BATCH: usize,
+                Opt: Optimizer<$T>,
+            > BatchLayer<$T, I_LEN, O_LEN, BATCH> for $layer_ty<$T, B, I_LEN, O_LEN, Opt>
+        where
+            [(); O_LEN * I_LEN]:,
+        {
+            type BatchBuffer = [[$T; O_LEN]; BATCH];
+
+            fn batch_buffer() -> Self::BatchBuffer {
+                [[0.; O_LEN]; BATCH]
+            }
+
+            fn batch_output(buffer: &Self::BatchBuffer) -> &[[$T; O_LEN]; BATCH] {
+                buffer
+            }
+
+            fn predict_batch(
+                &mut self,
+                i: &[[$T; I_LEN]; BATCH],
+                buffer: &mut [[$T; O_LEN]; BATCH],
+            ) -> Result<()> {
+                <$T>::gemm(false, true, BATCH, O_LEN, I_LEN, 1., flatten(i), &self.weights.value, 0., flatten_mut(buffer));
+
+                for o in buffer.iter_mut() {
+                    for (o, b) in o.iter_mut().zip(self.biasies.value.iter()) {
+                        *o += b;
+                    }
+                }
+                Ok(())
+            }
+
+            fn backpropagate_batch(
+                &mut self,
+                i: &[[$T; I_LEN]; BATCH],
+                _buffer: &[[$T; O_LEN]; BATCH],
+                gradient: &[[$T; O_LEN]; BATCH],
+            ) -> Result<[[$T; I_LEN]; BATCH]> {
+                let mut buffer = [[0.; I_LEN]; BATCH];
+                let scale = 1. / BATCH as $T;
+
+                <$T>::gemm(false, false, BATCH, I_LEN, O_LEN, 1., flatten(gradient), &self.weights.value, 0., flatten_mut(&mut buffer));
+
+                if self.frozen {
+                    return Ok(buffer);
+                }
+
+                <$T>::gemm(true, false, O_LEN, I_LEN, BATCH, scale, flatten(gradient), flatten(i), 1., &mut self.weights.gradient);
 
                 for j in 0..O_LEN {
-                    self.biasies[j] -= gradient[j] * self.lr;
+                    self.biasies.gradient[j] += gradient.iter().map(|g| g[j]).sum::<$T>() * scale;
+                }
+
+                if !self.accumulate {
+                    self.apply_gradients();
+                }
+
+                Ok(buffer)
+            }
+        }
 
+        /// The transposed layer multiplies by the transposed weights, without adding the biases.
+        impl<
+                B: Backend<$T> + MatrixMul<$T>,
+                const I_LEN: usize,
+                const O_LEN: usize,
+                Opt: Optimizer<$T>,
+            > Transpose<$T, I_LEN, O_LEN> for $layer_ty<$T, B, I_LEN, O_LEN, Opt>
+        where
+            [(); O_LEN * I_LEN]:,
+        {
+            fn predict_transposed(
+                &self,
+                i: impl StaticVec<$T, O_LEN>,
+                buffer: &mut impl StaticVec<$T, I_LEN>,
+            ) -> Result<()> {
+                <$T>::gemv(true, O_LEN, I_LEN, 1., &self.weights.value, i.moo_ref(), 0., buffer.mut_moo_ref());
+                Ok(())
+            }
+
+            fn backpropagate_transposed(
+                &mut self,
+                i: impl StaticVec<$T, O_LEN>,
+                gradient: impl StaticVec<$T, I_LEN>,
+            ) -> Result<[$T; O_LEN]> {
+                let mut buffer = [num!(0); O_LEN];
+                let gradient = gradient.moo_ref();
+                let input = i.moo_ref();
+
+                <$T>::gemv(false, O_LEN, I_LEN, 1., &self.weights.value, gradient, 0., &mut buffer);
+
+                if self.frozen {
+                    return Ok(buffer);
+                }
+
+                for j in 0..O_LEN {
                     for i in 0..I_LEN {
-                        weights[(j, i)] -= gradient[j] * input[i] * self.lr;
-                        buffer[i] += weights[(j, i)] * gradient[j];
+                        self.weights.gradient[j * I_LEN + i] += input[j] * gradient[i];
                     }
                 }
 
+                if !self.accumulate {
+                    self.apply_gradients();
+                }
+
+                Ok(buffer)
+            }
+
+            fn predict_batch_transposed<const BATCH: usize>(
+                &self,
+                i: &[[$T; O_LEN]; BATCH],
+                buffer: &mut [[$T; I_LEN]; BATCH],
+            ) -> Result<()> {
+                <$T>::gemm(false, false, BATCH, I_LEN, O_LEN, 1., flatten(i), &self.weights.value, 0., flatten_mut(buffer));
+                Ok(())
+            }
+
+            fn backpropagate_batch_transposed<const BATCH: usize>(
+                &mut self,
+                i: &[[$T; O_LEN]; BATCH],
+                gradient: &[[$T; I_LEN]; BATCH],
+            ) -> Result<[[$T; O_LEN]; BATCH]> {
+                let mut buffer = [[0.; O_LEN]; BATCH];
+                let scale = 1. / BATCH as $T;
+
+                <$T>::gemm(false, true, BATCH, O_LEN, I_LEN, 1., flatten(gradient), &self.weights.value, 0., flatten_mut(&mut buffer));
+
+                if self.frozen {
+                    return Ok(buffer);
+                }
+
+                <$T>::gemm(true, false, O_LEN, I_LEN, BATCH, scale, flatten(i), flatten(gradient), 1., &mut self.weights.gradient);
+
+                if !self.accumulate {
+                    self.apply_gradients();
+                }
+
                 Ok(buffer)
             }
         }
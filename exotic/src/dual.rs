@@ -0,0 +1,237 @@
+use crate::*;
+use std::{
+    cmp::Ordering,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+/// Dual number for forward-mode automatic differentiation.
+///
+/// Evaluating a function on `Dual::variable(x)` gives both `f(x)` and `f'(x)`.
+/// Methods share names with the ones on `f32` and `f64`, so the same closure can be used for floats and dual numbers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Dual<T> {
+    pub value: T,
+    pub derivative: T,
+}
+
+impl<T: Float> Dual<T> {
+    pub fn variable(value: T) -> Self {
+        Self {
+            value,
+            derivative: T::_1,
+        }
+    }
+
+    pub fn constant(value: T) -> Self {
+        Self {
+            value,
+            derivative: T::_0,
+        }
+    }
+
+    pub fn exp_(self) -> Self {
+        let exp = self.value.exp_();
+        Self {
+            value: exp,
+            derivative: self.derivative * exp,
+        }
+    }
+
+    pub fn powi_(self, n: i32) -> Self {
+        Self {
+            value: self.value.powi_(n),
+            derivative: self.derivative * T::from_f64(n as f64) * self.value.powi_(n - 1),
+        }
+    }
+}
+
+/// Derivative of `f` at `x`.
+pub fn derivative<T: Float>(f: impl Fn(Dual<T>) -> Dual<T>, x: T) -> T {
+    f(Dual::variable(x)).derivative
+}
+
+impl<T: Float> Add for Dual<T> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            value: self.value + rhs.value,
+            derivative: self.derivative + rhs.derivative,
+        }
+    }
+}
+
+impl<T: Float> Sub for Dual<T> {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            value: self.value - rhs.value,
+            derivative: self.derivative - rhs.derivative,
+        }
+    }
+}
+
+impl<T: Float> Mul for Dual<T> {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self {
+            value: self.value * rhs.value,
+            derivative: self.derivative * rhs.value + self.value * rhs.derivative,
+        }
+    }
+}
+
+impl<T: Float> Div for Dual<T> {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        Self {
+            value: self.value / rhs.value,
+            derivative: (self.derivative * rhs.value - self.value * rhs.derivative)
+                / (rhs.value * rhs.value),
+        }
+    }
+}
+
+impl<T: Float> Neg for Dual<T> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self {
+            value: -self.value,
+            derivative: -self.derivative,
+        }
+    }
+}
+
+macro_rules! impl_scalar_ops {
+    ($($op: ident, $fn: ident);*) => {$(
+        impl<T: Float> $op<T> for Dual<T> {
+            type Output = Self;
+            fn $fn(self, rhs: T) -> Self {
+                self.$fn(Dual::constant(rhs))
+            }
+        }
+    )*};
+}
+
+impl_scalar_ops!(Add, add; Sub, sub; Mul, mul; Div, div);
+
+impl<T: Float> PartialEq<T> for Dual<T> {
+    fn eq(&self, other: &T) -> bool {
+        self.value == *other
+    }
+}
+
+impl<T: Float + PartialOrd> PartialOrd<T> for Dual<T> {
+    fn partial_cmp(&self, other: &T) -> Option<Ordering> {
+        self.value.partial_cmp(other)
+    }
+}
+
+impl<T: Float + PartialOrd> PartialOrd for Dual<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.value.partial_cmp(&other.value)
+    }
+}
+
+macro_rules! impl_dual {
+    ($T: ty) => {
+        impl Add<Dual<$T>> for $T {
+            type Output = Dual<$T>;
+            fn add(self, rhs: Dual<$T>) -> Dual<$T> {
+                Dual::constant(self) + rhs
+            }
+        }
+
+        impl Sub<Dual<$T>> for $T {
+            type Output = Dual<$T>;
+            fn sub(self, rhs: Dual<$T>) -> Dual<$T> {
+                Dual::constant(self) - rhs
+            }
+        }
+
+        impl Mul<Dual<$T>> for $T {
+            type Output = Dual<$T>;
+            fn mul(self, rhs: Dual<$T>) -> Dual<$T> {
+                Dual::constant(self) * rhs
+            }
+        }
+
+        impl Div<Dual<$T>> for $T {
+            type Output = Dual<$T>;
+            fn div(self, rhs: Dual<$T>) -> Dual<$T> {
+                Dual::constant(self) / rhs
+            }
+        }
+
+        impl Dual<$T> {
+            /// Apply a function with a known derivative, using the chain rule.
+            fn chain(self, value: $T, derivative: $T) -> Self {
+                Self {
+                    value,
+                    derivative: self.derivative * derivative,
+                }
+            }
+
+            pub fn exp(self) -> Self {
+                let exp = self.value.exp();
+                self.chain(exp, exp)
+            }
+
+            pub fn ln(self) -> Self {
+                self.chain(self.value.ln(), 1. / self.value)
+            }
+
+            pub fn sqrt(self) -> Self {
+                let sqrt = self.value.sqrt();
+                self.chain(sqrt, 0.5 / sqrt)
+            }
+
+            pub fn powi(self, n: i32) -> Self {
+                self.chain(self.value.powi(n), n as $T * self.value.powi(n - 1))
+            }
+
+            pub fn powf(self, n: $T) -> Self {
+                self.chain(self.value.powf(n), n * self.value.powf(n - 1.))
+            }
+
+            pub fn recip(self) -> Self {
+                self.chain(1. / self.value, -1. / (self.value * self.value))
+            }
+
+            pub fn abs(self) -> Self {
+                self.chain(self.value.abs(), self.value.signum())
+            }
+
+            pub fn sin(self) -> Self {
+                self.chain(self.value.sin(), self.value.cos())
+            }
+
+            pub fn cos(self) -> Self {
+                self.chain(self.value.cos(), -self.value.sin())
+            }
+
+            pub fn tanh(self) -> Self {
+                let tanh = self.value.tanh();
+                self.chain(tanh, 1. - tanh * tanh)
+            }
+
+            pub fn max(self, other: $T) -> Self {
+                if self.value >= other {
+                    self
+                } else {
+                    Dual::constant(other)
+                }
+            }
+
+            pub fn min(self, other: $T) -> Self {
+                if self.value <= other {
+                    self
+                } else {
+                    Dual::constant(other)
+                }
+            }
+        }
+    };
+}
+
+impl_dual!(f32);
+impl_dual!(f64);
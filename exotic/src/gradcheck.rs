@@ -0,0 +1,97 @@
+use crate::{parameters::Parameters, *};
+
+/// Result of comparing the gradients from `Layer::backpropagate` with a numerical approximation.
+#[derive(Clone, Debug)]
+pub struct GradCheck<const LEN: usize> {
+    /// Relative error of the gradient of every input element.
+    /// Gradients smaller than the step size are compared absolutely.
+    pub errors: [f64; LEN],
+    /// Relative error of the gradient of every parameter, in the order of `Parameters::visit_params`.
+    pub param_errors: Vec<f64>,
+    /// The largest error of both the inputs and the parameters.
+    pub max_error: f64,
+}
+
+/// Check the input and parameter gradients of a layer with central differences.
+///
+/// The loss that is differentiated is the dot product of the output of the layer and `gradient`,
+/// which makes `gradient` the gradient of the loss with respect to the output.
+/// The layer is cloned before every call, so its parameters are never updated.
+/// The parameter gradients are accumulated by a single `backpropagate`, with the layer unfrozen.
+/// ```ignore
+/// let check = gradcheck(&Tanh::<f64, 3>::default(), &[0.5, -1., 2.], &[1., 1., 1.], 1e-5)?;
+/// assert!(check.max_error < 1e-6);
+/// ```
+pub fn gradcheck<
+    T: Float + Into<f64>,
+    L: Layer<T, I_LEN, O_LEN, BUFFER_LEN> + Parameters<T> + Clone,
+    const I_LEN: usize,
+    const O_LEN: usize,
+    const BUFFER_LEN: usize,
+>(
+    layer: &L,
+    i: &[T; I_LEN],
+    gradient: &[T; O_LEN],
+    h: f64,
+) -> Result<GradCheck<I_LEN>> {
+    let loss = |layer: &L, i: &[T; I_LEN]| -> Result<f64> {
+        let mut buffer = [T::_0; BUFFER_LEN];
+        layer.clone().predict(i, &mut buffer)?;
+        Ok(L::output(&buffer)
+            .iter()
+            .zip(gradient.iter())
+            .map(|(&o, &g)| (o * g).into())
+            .sum())
+    };
+    let error = |numeric: f64, analytic: f64| {
+        (numeric - analytic).abs() / (numeric.abs() + analytic.abs()).max(h)
+    };
+
+    let mut buffer = [T::_0; BUFFER_LEN];
+    let mut analytic = layer.clone();
+    analytic.set_frozen(false);
+    analytic.set_accumulate(true);
+    analytic.zero_grad();
+    analytic.predict(i, &mut buffer)?;
+    let input_gradient = analytic.backpropagate(i, &buffer, gradient)?;
+
+    let mut errors = [0.; I_LEN];
+    for n in 0..I_LEN {
+        let (mut a, mut b) = (*i, *i);
+        a[n] = a[n] + T::from_f64(h);
+        b[n] = b[n] - T::from_f64(h);
+        let numeric = (loss(layer, &a)? - loss(layer, &b)?) / (2. * h);
+        errors[n] = error(numeric, input_gradient.moo_ref()[n].into());
+    }
+
+    // Add `d` to parameter number `p`, counted across all the tensors of the layer.
+    let perturbed = |p: usize, d: f64| -> L {
+        let mut layer = layer.clone();
+        let mut offset = 0;
+        layer.visit_params_mut(&mut |_, params| {
+            if (offset..offset + params.len()).contains(&p) {
+                params[p - offset] = params[p - offset] + T::from_f64(d);
+            }
+            offset += params.len();
+        });
+        layer
+    };
+
+    let mut param_gradients = vec![];
+    analytic.visit_grads(&mut |_, grads| param_gradients.extend(grads.iter().map(|&g| g.into())));
+
+    let mut param_errors = Vec::with_capacity(param_gradients.len());
+    for (p, &analytic) in param_gradients.iter().enumerate() {
+        let numeric = (loss(&perturbed(p, h), i)? - loss(&perturbed(p, -h), i)?) / (2. * h);
+        param_errors.push(error(numeric, analytic));
+    }
+
+    Ok(GradCheck {
+        max_error: errors
+            .iter()
+            .chain(param_errors.iter())
+            .fold(0., |max, &e| if e > max { e } else { max }),
+        errors,
+        param_errors,
+    })
+}
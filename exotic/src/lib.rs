@@ -17,6 +17,7 @@ use slas::prelude::*;
 pub trait Layer<T: Float, const I_LEN: usize, const O_LEN: usize, const BUFFER_LEN: usize> {
     const O_LEN: usize = O_LEN;
     const I_LEN: usize = I_LEN;
+    const BUFFER_LEN: usize = BUFFER_LEN;
     type Gradient: StaticVec<T, I_LEN>;
 
     fn predict(
@@ -30,6 +31,157 @@ pub trait Layer<T: Float, const I_LEN: usize, const O_LEN: usize, const BUFFER_L
         buffer: &impl StaticVec<T, BUFFER_LEN>,
         gradient: impl StaticVec<T, O_LEN>,
     ) -> Result<Self::Gradient>;
+
+    /// The output of the layer, which is stored at the end of its buffer.
+    fn output(buffer: &impl StaticVec<T, BUFFER_LEN>) -> &[T; O_LEN] {
+        assert!(O_LEN <= BUFFER_LEN);
+        unsafe { buffer.static_slice_unchecked::<O_LEN>(BUFFER_LEN - O_LEN) }
+    }
+
+    /// Set the learning rate of all trainable parameters in the layer.
+    fn set_lr(&mut self, _lr: T) {}
+
+    /// When `accumulate` is set, `backpropagate` only adds the gradients of the parameters to internal buffers,
+    /// instead of also applying them.
+    /// The accumulated gradients are summed, so the learning rate should be scaled by the number of samples, to get their mean.
+    /// ```ignore
+    /// net.set_accumulate(true);
+    /// for (i, y) in samples {
+    ///     net.predict(i, &mut buffer)?;
+    ///     net.backpropagate(i, &buffer, ...)?;
+    /// }
+    /// net.apply_gradients();
+    /// ```
+    fn set_accumulate(&mut self, _accumulate: bool) {}
+
+    /// Whether the layer is set to accumulate its gradients, by `set_accumulate`.
+    fn accumulating(&self) -> bool {
+        false
+    }
+
+    /// Update the parameters with the accumulated gradients, and reset the gradients to zero.
+    fn apply_gradients(&mut self) {}
+
+    /// A frozen layer still passes the gradient on to its input in `backpropagate`,
+    /// but doesn't compute the gradients of its parameters, or update them.
+    /// `apply_gradients` also leaves the parameters of a frozen layer alone.
+    fn set_frozen(&mut self, _frozen: bool) {}
+
+    /// Discard the accumulated gradients.
+    fn zero_grad(&mut self) {}
+}
+
+/// Trait for layers that can process a batch of `BATCH` samples at a time.
+/// The gradients of the parameters are averaged over the batch, before being applied.
+pub trait BatchLayer<T: Float, const I_LEN: usize, const O_LEN: usize, const BATCH: usize> {
+    /// Holds the output of the layer for every sample in a batch.
+    type BatchBuffer;
+
+    fn batch_buffer() -> Self::BatchBuffer;
+    fn batch_output(buffer: &Self::BatchBuffer) -> &[[T; O_LEN]; BATCH];
+
+    fn predict_batch(
+        &mut self,
+        i: &[[T; I_LEN]; BATCH],
+        buffer: &mut Self::BatchBuffer,
+    ) -> Result<()>;
+    fn backpropagate_batch(
+        &mut self,
+        i: &[[T; I_LEN]; BATCH],
+        buffer: &Self::BatchBuffer,
+        gradient: &[[T; O_LEN]; BATCH],
+    ) -> Result<[[T; I_LEN]; BATCH]>;
+}
+
+/// Layers that are batched by running every sample of the batch on its own,
+/// with a `BatchLayer` implementation from [`sample_batch_layer!`].
+pub trait SampleLayer<T: Float, const I_LEN: usize, const O_LEN: usize>:
+    Layer<T, I_LEN, O_LEN, O_LEN, Gradient = [T; I_LEN]>
+{
+    /// Backpropagate a single sample of a batch, with the gradients of the parameters scaled by `scale`,
+    /// and without applying them.
+    /// The default calls `backpropagate`, which is only right for layers without parameters.
+    fn backpropagate_sample(
+        &mut self,
+        i: &[T; I_LEN],
+        o: &[T; O_LEN],
+        gradient: &[T; O_LEN],
+        _scale: T,
+    ) -> Result<[T; I_LEN]> {
+        self.backpropagate(i, o, gradient)
+    }
+}
+
+/// Implement `BatchLayer` for a `SampleLayer`, given the generics of the layer in brackets.
+/// The gradients of the parameters are averaged over the batch, and applied once at the end,
+/// unless the layer is accumulating.
+/// ```ignore
+/// sample_batch_layer!([T: Float, const LEN: usize,] T, LEN, LEN, Tanh<T, LEN>);
+/// ```
+#[macro_export]
+macro_rules! sample_batch_layer {
+    ([$($gen: tt)*] $T: ty, $I: ident, $O: ident, $layer: ty $(where $($bound: tt)*)?) => {
+        impl<$($gen)* const BATCH: usize> $crate::BatchLayer<$T, $I, $O, BATCH> for $layer
+        $(where $($bound)*)?
+        {
+            type BatchBuffer = [[$T; $O]; BATCH];
+
+            fn batch_buffer() -> Self::BatchBuffer {
+                [[<$T as $crate::prelude::Float>::_0; $O]; BATCH]
+            }
+
+            fn batch_output(buffer: &Self::BatchBuffer) -> &[[$T; $O]; BATCH] {
+                buffer
+            }
+
+            fn predict_batch(&mut self, i: &[[$T; $I]; BATCH], buffer: &mut Self::BatchBuffer) -> $crate::prelude::Result<()> {
+                for (i, o) in i.iter().zip(buffer.iter_mut()) {
+                    $crate::Layer::predict(self, i, o)?;
+                }
+                Ok(())
+            }
+
+            fn backpropagate_batch(
+                &mut self,
+                i: &[[$T; $I]; BATCH],
+                buffer: &Self::BatchBuffer,
+                gradient: &[[$T; $O]; BATCH],
+            ) -> $crate::prelude::Result<[[$T; $I]; BATCH]> {
+                let scale = <$T as $crate::prelude::Float>::_1 / <$T as $crate::prelude::Float>::from_f64(BATCH as f64);
+                let mut ret = [[<$T as $crate::prelude::Float>::_0; $I]; BATCH];
+                for n in 0..BATCH {
+                    ret[n] = $crate::SampleLayer::backpropagate_sample(self, &i[n], &buffer[n], &gradient[n], scale)?;
+                }
+                if !$crate::Layer::accumulating(self) {
+                    $crate::Layer::apply_gradients(self);
+                }
+                Ok(ret)
+            }
+        }
+    };
+}
+
+/// The cached output of layer `N` of a model.
+/// Implemented by the caches generated by `model!`, and used through their `layer::<N>()` method.
+pub trait LayerOutput<const N: usize> {
+    type Output;
+
+    fn layer_output(&self) -> &Self::Output;
+}
+
+/// The largest of `lens`, in a form that can be used for array lengths.
+/// Used by `model!` to size the buffers of `infer`.
+#[doc(hidden)]
+pub const fn max_len(lens: &[usize]) -> usize {
+    let mut max = 0;
+    let mut n = 0;
+    while n < lens.len() {
+        if lens[n] > max {
+            max = lens[n];
+        }
+        n += 1;
+    }
+    max
 }
 
 pub fn onehot<T: Float, const LEN: usize>(i: usize) -> [T; LEN] {
@@ -40,6 +192,18 @@ pub fn onehot<T: Float, const LEN: usize>(i: usize) -> [T; LEN] {
 
 #[macro_use]
 pub mod activation;
+mod blas;
 pub mod dense;
+pub mod dual;
+pub mod gradcheck;
+pub mod loss;
+pub mod maxout;
+pub mod optimizer;
+pub mod param;
+pub mod parameters;
+pub mod prelu;
+pub mod scheduler;
+pub mod serialization;
+pub mod transpose;
 pub use slas;
 pub mod prelude;
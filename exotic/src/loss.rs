@@ -0,0 +1,299 @@
+use crate::*;
+
+/// Loss function, comparing the output `o` of a model with the target `y`.
+///
+/// Returns the loss, and its gradient with respect to `o`,
+/// which is what should be passed to `Layer::backpropagate`.
+/// ```ignore
+/// net.predict(i, &mut buffer)?;
+/// let (cost, dy) = Mse.loss(Net::output(&buffer), y);
+/// net.backpropagate(i, &buffer, dy)?;
+/// ```
+pub trait Loss<T: Float, const LEN: usize> {
+    fn loss(&self, o: impl StaticVec<T, LEN>, y: impl StaticVec<T, LEN>) -> (T, [T; LEN]);
+}
+
+/// Loss function for a target given as the index of the correct class.
+pub trait SparseLoss<T: Float, const LEN: usize> {
+    fn sparse(&self, o: impl StaticVec<T, LEN>, label: usize) -> (T, [T; LEN]);
+}
+
+/// Mean squared error.
+#[derive(Clone, Copy, Default)]
+pub struct Mse;
+
+/// Mean absolute error.
+#[derive(Clone, Copy, Default)]
+pub struct Mae;
+
+/// Squared error for residuals smaller than `delta`, and absolute error for larger ones.
+#[derive(Clone, Copy)]
+pub struct Huber<T: Float> {
+    pub delta: T,
+}
+
+impl<T: Float> Huber<T> {
+    pub fn new(delta: T) -> Self {
+        Self { delta }
+    }
+}
+
+/// Binary cross-entropy, for outputs that are independent probabilities (e.g. from `Sigmoid`).
+#[derive(Clone, Copy, Default)]
+pub struct BinaryCrossEntropy;
+
+/// Categorical cross-entropy, for outputs that are a probability distribution (e.g. from `Softmax`).
+///
+/// The target is either a distribution, like the ones from `onehot`, or an integer label passed to `sparse`.
+#[derive(Clone, Copy, Default)]
+pub struct CategoricalCrossEntropy;
+
+/// Categorical cross-entropy of the softmax of the output, for models that output logits.
+///
+/// The gradient with respect to the logits is simply `softmax(o) - y`,
+/// which is more stable than backpropagating `CategoricalCrossEntropy` through a `Softmax` layer.
+/// A `Softmax` layer is then only needed to get probabilities at inference.
+#[derive(Clone, Copy, Default)]
+pub struct SoftmaxCrossEntropy;
+
+/// Hinge loss, for targets of -1 or 1.
+#[derive(Clone, Copy, Default)]
+pub struct Hinge;
+
+/// Kullback-Leibler divergence of the output distribution from the target distribution.
+#[derive(Clone, Copy, Default)]
+pub struct KlDivergence;
+
+/// Cosine embedding loss.
+///
+/// If `similar` is set, the output is pushed to point in the same direction as the target,
+/// otherwise their cosine similarity is pushed below `margin`.
+#[derive(Clone, Copy)]
+pub struct CosineEmbedding<T: Float> {
+    pub margin: T,
+    pub similar: bool,
+}
+
+impl<T: Float> CosineEmbedding<T> {
+    pub fn new(similar: bool) -> Self {
+        Self {
+            margin: T::_0,
+            similar,
+        }
+    }
+}
+
+macro_rules! impl_loss {
+    ($T: ty) => {
+        impl<const LEN: usize> SparseLoss<$T, LEN> for CategoricalCrossEntropy {
+            fn sparse(&self, o: impl StaticVec<$T, LEN>, label: usize) -> ($T, [$T; LEN]) {
+                let p = o.moo_ref()[label].max(<$T>::EPSILON);
+                let mut gradient = [0.; LEN];
+                gradient[label] = -1. / p;
+                (-p.ln(), gradient)
+            }
+        }
+
+        impl<const LEN: usize> SparseLoss<$T, LEN> for SoftmaxCrossEntropy {
+            fn sparse(&self, o: impl StaticVec<$T, LEN>, label: usize) -> ($T, [$T; LEN]) {
+                self.loss(o, &onehot::<$T, LEN>(label))
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for Mse {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    let d = o[n] - y[n];
+                    loss += d * d;
+                    gradient[n] = 2. * d / LEN as $T;
+                }
+                (loss / LEN as $T, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for Mae {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    let d = o[n] - y[n];
+                    loss += d.abs();
+                    gradient[n] = if d == 0. { 0. } else { d.signum() / LEN as $T };
+                }
+                (loss / LEN as $T, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for Huber<$T> {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    let d = o[n] - y[n];
+                    if d.abs() <= self.delta {
+                        loss += d * d / 2.;
+                        gradient[n] = d / LEN as $T;
+                    } else {
+                        loss += self.delta * (d.abs() - self.delta / 2.);
+                        gradient[n] = self.delta * d.signum() / LEN as $T;
+                    }
+                }
+                (loss / LEN as $T, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for BinaryCrossEntropy {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    let p = o[n].clamp(<$T>::EPSILON, 1. - <$T>::EPSILON);
+                    loss -= y[n] * p.ln() + (1. - y[n]) * (1. - p).ln();
+                    gradient[n] = (p - y[n]) / (p * (1. - p)) / LEN as $T;
+                }
+                (loss / LEN as $T, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for CategoricalCrossEntropy {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    let p = o[n].max(<$T>::EPSILON);
+                    loss -= y[n] * p.ln();
+                    gradient[n] = -y[n] / p;
+                }
+                (loss, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for SoftmaxCrossEntropy {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let max = o.iter().fold(o[0], |max, &x| x.max(max));
+                let log_sum = max + o.iter().map(|&x| (x - max).exp()).sum::<$T>().ln();
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    loss -= y[n] * (o[n] - log_sum);
+                    gradient[n] = (o[n] - log_sum).exp() - y[n];
+                }
+                (loss, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for Hinge {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    let margin = 1. - y[n] * o[n];
+                    if margin > 0. {
+                        loss += margin;
+                        gradient[n] = -y[n] / LEN as $T;
+                    }
+                }
+                (loss / LEN as $T, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for KlDivergence {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let mut gradient = [0.; LEN];
+                let mut loss = 0.;
+                for n in 0..LEN {
+                    if y[n] > 0. {
+                        let p = o[n].max(<$T>::EPSILON);
+                        loss += y[n] * (y[n] / p).ln();
+                        gradient[n] = -y[n] / p;
+                    }
+                }
+                (loss, gradient)
+            }
+        }
+
+        impl<const LEN: usize> Loss<$T, LEN> for CosineEmbedding<$T> {
+            fn loss(
+                &self,
+                o: impl StaticVec<$T, LEN>,
+                y: impl StaticVec<$T, LEN>,
+            ) -> ($T, [$T; LEN]) {
+                let (o, y) = (o.moo_ref(), y.moo_ref());
+                let dot: $T = o.iter().zip(y.iter()).map(|(o, y)| o * y).sum();
+                let o_norm = o
+                    .iter()
+                    .map(|o| o * o)
+                    .sum::<$T>()
+                    .sqrt()
+                    .max(<$T>::EPSILON);
+                let y_norm = y
+                    .iter()
+                    .map(|y| y * y)
+                    .sum::<$T>()
+                    .sqrt()
+                    .max(<$T>::EPSILON);
+                let cos = dot / (o_norm * y_norm);
+
+                // Gradient of the cosine similarity with respect to o.
+                let mut gradient = [0.; LEN];
+                for n in 0..LEN {
+                    gradient[n] = y[n] / (o_norm * y_norm) - cos * o[n] / (o_norm * o_norm);
+                }
+
+                if self.similar {
+                    gradient.iter_mut().for_each(|g| *g = -*g);
+                    (1. - cos, gradient)
+                } else if cos > self.margin {
+                    (cos - self.margin, gradient)
+                } else {
+                    (0., [0.; LEN])
+                }
+            }
+        }
+    };
+}
+
+impl_loss!(f32);
+impl_loss!(f64);
@@ -0,0 +1,238 @@
+use crate::{
+    optimizer::*,
+    param::{impl_training, Param},
+    parameters::Parameters,
+    serialization::Serialization,
+    *,
+};
+
+/// Maxout layer, where every output is the maximum of `K` trainable linear functions of the input.
+///
+/// The weights of piece `k` of output `o` are row `o * K + k` of `weights`.
+/// Like `DenseLayer`, the parameters are updated by `backpropagate` through the optimizer.
+#[derive(Clone, Copy)]
+pub struct Maxout<
+    T: Float,
+    const I_LEN: usize,
+    const O_LEN: usize,
+    const K: usize,
+    Opt: Optimizer<T> = Sgd<T>,
+> where
+    [(); O_LEN * K * I_LEN]:,
+    [(); O_LEN * K]:,
+{
+    pub weights: Param<[T; O_LEN * K * I_LEN], [Opt::State; O_LEN * K * I_LEN]>,
+    pub biasies: Param<[T; O_LEN * K], [Opt::State; O_LEN * K]>,
+    pub optimizer: Opt,
+    accumulate: bool,
+    frozen: bool,
+}
+
+impl<
+        T: Float + PartialOrd,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        const K: usize,
+        Opt: Optimizer<T>,
+    > Maxout<T, I_LEN, O_LEN, K, Opt>
+where
+    [(); O_LEN * K * I_LEN]:,
+    [(); O_LEN * K]:,
+{
+    pub fn random(optimizer: Opt) -> Self {
+        let xavier = || -> T {
+            (random::<T>() - num!(0.5)) * (T::_2 / (T::from_f64((O_LEN + I_LEN) as f64)))
+        };
+
+        let mut weights = [T::_0; O_LEN * K * I_LEN];
+        let mut biasies = [T::_0; O_LEN * K];
+        weights.iter_mut().for_each(|n| *n = xavier());
+        biasies.iter_mut().for_each(|n| *n = xavier());
+        Self {
+            weights: Param {
+                value: weights,
+                gradient: [T::_0; O_LEN * K * I_LEN],
+                state: [Opt::State::default(); O_LEN * K * I_LEN],
+            },
+            biasies: Param {
+                value: biasies,
+                gradient: [T::_0; O_LEN * K],
+                state: [Opt::State::default(); O_LEN * K],
+            },
+            optimizer,
+            accumulate: false,
+            frozen: false,
+        }
+    }
+
+    /// The row of the largest piece of every output, and its value.
+    fn argmax(&self, i: &[T; I_LEN]) -> [(usize, T); O_LEN] {
+        let mut max = [(0, T::_0); O_LEN];
+        for (o, max) in max.iter_mut().enumerate() {
+            for row in o * K..(o + 1) * K {
+                let z = self.weights.value[row * I_LEN..(row + 1) * I_LEN]
+                    .iter()
+                    .zip(i.iter())
+                    .fold(self.biasies.value[row], |z, (&w, &i)| z + w * i);
+                if row == o * K || z > max.1 {
+                    *max = (row, z);
+                }
+            }
+        }
+        max
+    }
+
+    /// Add the gradients of the parameters (scaled by `scale`) unless the layer is frozen,
+    /// and return the gradient of the input.
+    /// Only the largest piece of every output receives a gradient.
+    fn accumulate_gradients(
+        &mut self,
+        i: &[T; I_LEN],
+        gradient: &[T; O_LEN],
+        scale: T,
+    ) -> [T; I_LEN] {
+        let mut buffer = [T::_0; I_LEN];
+        for (o, (row, _)) in self.argmax(i).into_iter().enumerate() {
+            let (g, weights) = (gradient[o], row * I_LEN..(row + 1) * I_LEN);
+            for (b, &w) in buffer.iter_mut().zip(&self.weights.value[weights.clone()]) {
+                *b = *b + w * g;
+            }
+            if self.frozen {
+                continue;
+            }
+            self.biasies.gradient[row] = self.biasies.gradient[row] + g * scale;
+            for (w, &i) in self.weights.gradient[weights].iter_mut().zip(i) {
+                *w = *w + g * i * scale;
+            }
+        }
+        buffer
+    }
+
+    /// Add the accumulated gradients of another copy of the layer to the gradients of this one.
+    pub fn add_gradients(&mut self, other: &Self) {
+        self.weights.add_gradients(&other.weights);
+        self.biasies.add_gradients(&other.biasies);
+    }
+}
+
+impl<
+        T: Float + PartialOrd,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        const K: usize,
+        Opt: Optimizer<T>,
+    > Layer<T, I_LEN, O_LEN, O_LEN> for Maxout<T, I_LEN, O_LEN, K, Opt>
+where
+    [(); O_LEN * K * I_LEN]:,
+    [(); O_LEN * K]:,
+{
+    type Gradient = [T; I_LEN];
+
+    fn predict(
+        &mut self,
+        i: impl StaticVec<T, I_LEN>,
+        buffer: &mut impl StaticVec<T, O_LEN>,
+    ) -> Result<()> {
+        let buffer = buffer.mut_moo_ref();
+        for (o, (_, z)) in self.argmax(i.moo_ref()).into_iter().enumerate() {
+            buffer[o] = z;
+        }
+        Ok(())
+    }
+
+    /// Here buffer is shadowed, so a NullVec can safely be passed.
+    fn backpropagate(
+        &mut self,
+        i: impl StaticVec<T, I_LEN>,
+        _buffer: &impl StaticVec<T, O_LEN>,
+        gradient: impl StaticVec<T, O_LEN>,
+    ) -> Result<[T; I_LEN]> {
+        let buffer = self.accumulate_gradients(i.moo_ref(), gradient.moo_ref(), T::_1);
+        if !self.accumulate {
+            self.apply_gradients();
+        }
+        Ok(buffer)
+    }
+
+    impl_training!(T; weights, biasies);
+}
+
+impl<
+        T: Float + PartialOrd,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        const K: usize,
+        Opt: Optimizer<T>,
+    > SampleLayer<T, I_LEN, O_LEN> for Maxout<T, I_LEN, O_LEN, K, Opt>
+where
+    [(); O_LEN * K * I_LEN]:,
+    [(); O_LEN * K]:,
+{
+    fn backpropagate_sample(
+        &mut self,
+        i: &[T; I_LEN],
+        _o: &[T; O_LEN],
+        gradient: &[T; O_LEN],
+        scale: T,
+    ) -> Result<[T; I_LEN]> {
+        Ok(self.accumulate_gradients(i, gradient, scale))
+    }
+}
+
+crate::sample_batch_layer!(
+    [T: Float + PartialOrd, const I_LEN: usize, const O_LEN: usize, const K: usize, Opt: Optimizer<T>,]
+    T, I_LEN, O_LEN, Maxout<T, I_LEN, O_LEN, K, Opt>
+    where [(); O_LEN * K * I_LEN]:, [(); O_LEN * K]:
+);
+
+impl<
+        T: Float + Serialization,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        const K: usize,
+        Opt: Optimizer<T>,
+    > Serialization for Maxout<T, I_LEN, O_LEN, K, Opt>
+where
+    [(); O_LEN * K * I_LEN]:,
+    [(); O_LEN * K]:,
+{
+    fn serialize_into(&self, writer: &mut dyn std::io::Write) -> Result<()> {
+        self.weights.value.serialize_into(writer)?;
+        self.biasies.value.serialize_into(writer)?;
+        self.weights.state.serialize_into(writer)?;
+        self.biasies.state.serialize_into(writer)?;
+        self.optimizer.serialize_into(writer)
+    }
+
+    fn deserialize_from(&mut self, reader: &mut dyn std::io::Read) -> Result<()> {
+        self.weights.value.deserialize_from(reader)?;
+        self.biasies.value.deserialize_from(reader)?;
+        self.weights.state.deserialize_from(reader)?;
+        self.biasies.state.deserialize_from(reader)?;
+        self.optimizer.deserialize_from(reader)
+    }
+}
+
+impl<T: Float, const I_LEN: usize, const O_LEN: usize, const K: usize, Opt: Optimizer<T>>
+    Parameters<T> for Maxout<T, I_LEN, O_LEN, K, Opt>
+where
+    [(); O_LEN * K * I_LEN]:,
+    [(); O_LEN * K]:,
+{
+    const PARAM_COUNT: usize = O_LEN * K * I_LEN + O_LEN * K;
+
+    fn visit_params(&self, visitor: &mut impl FnMut(&str, &[T])) {
+        visitor("weights", &self.weights.value);
+        visitor("biasies", &self.biasies.value);
+    }
+
+    fn visit_params_mut(&mut self, visitor: &mut impl FnMut(&str, &mut [T])) {
+        visitor("weights", &mut self.weights.value);
+        visitor("biasies", &mut self.biasies.value);
+    }
+
+    fn visit_grads(&self, visitor: &mut impl FnMut(&str, &[T])) {
+        visitor("weights", &self.weights.gradient);
+        visitor("biasies", &self.biasies.gradient);
+    }
+}
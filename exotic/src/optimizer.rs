@@ -0,0 +1,381 @@
+use crate::{serialization::Serialization, *};
+use std::io::{Read, Write};
+
+/// Update rule used by trainable layers.
+///
+/// Layers compute the gradient of their parameters during `backpropagate`,
+/// and then hand each parameter tensor, together with its gradient, to the optimizer.
+///
+/// Serializing an optimizer only saves its internal state (e.g. a step counter), not its hyperparameters.
+pub trait Optimizer<T: Float>: Serialization {
+    /// State kept for every single parameter (e.g. a velocity for momentum).
+    /// It is stored by the layer, next to the parameter it belongs to.
+    type State: Copy + Default + Serialization;
+
+    /// Called once every time a layer is about to update its parameters.
+    fn step(&mut self) {}
+
+    /// Update `params` in-place, given the gradient of the loss with respect to each of them.
+    fn update(&mut self, params: &mut [T], gradient: &[T], state: &mut [Self::State]);
+
+    /// The learning rate, which is what a [`crate::scheduler::Scheduler`] adjusts.
+    fn lr(&self) -> T;
+    fn set_lr(&mut self, lr: T);
+}
+
+/// Plain stochastic gradient descent.
+#[derive(Clone, Copy)]
+pub struct Sgd<T: Float> {
+    pub lr: T,
+}
+
+impl<T: Float> Sgd<T> {
+    pub fn new(lr: T) -> Self {
+        Self { lr }
+    }
+}
+
+impl<T: Float> Optimizer<T> for Sgd<T> {
+    type State = ();
+
+    fn lr(&self) -> T {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: T) {
+        self.lr = lr
+    }
+
+    fn update(&mut self, params: &mut [T], gradient: &[T], _: &mut [()]) {
+        for (p, g) in params.iter_mut().zip(gradient) {
+            *p = *p - *g * self.lr;
+        }
+    }
+}
+
+/// Stochastic gradient descent with momentum.
+/// The state of each parameter is its velocity.
+#[derive(Clone, Copy)]
+pub struct Momentum<T: Float> {
+    pub lr: T,
+    pub momentum: T,
+}
+
+impl<T: Float> Momentum<T> {
+    pub fn new(lr: T, momentum: T) -> Self {
+        Self { lr, momentum }
+    }
+}
+
+impl<T: Float + Default + Serialization> Optimizer<T> for Momentum<T> {
+    type State = T;
+
+    fn lr(&self) -> T {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: T) {
+        self.lr = lr
+    }
+
+    fn update(&mut self, params: &mut [T], gradient: &[T], state: &mut [T]) {
+        for ((p, g), v) in params.iter_mut().zip(gradient).zip(state) {
+            *v = *v * self.momentum + *g;
+            *p = *p - *v * self.lr;
+        }
+    }
+}
+
+/// Stochastic gradient descent with Nesterov momentum.
+#[derive(Clone, Copy)]
+pub struct Nesterov<T: Float> {
+    pub lr: T,
+    pub momentum: T,
+}
+
+impl<T: Float> Nesterov<T> {
+    pub fn new(lr: T, momentum: T) -> Self {
+        Self { lr, momentum }
+    }
+}
+
+impl<T: Float + Default + Serialization> Optimizer<T> for Nesterov<T> {
+    type State = T;
+
+    fn lr(&self) -> T {
+        self.lr
+    }
+
+    fn set_lr(&mut self, lr: T) {
+        self.lr = lr
+    }
+
+    fn update(&mut self, params: &mut [T], gradient: &[T], state: &mut [T]) {
+        for ((p, g), v) in params.iter_mut().zip(gradient).zip(state) {
+            *v = *v * self.momentum + *g;
+            *p = *p - (*g + *v * self.momentum) * self.lr;
+        }
+    }
+}
+
+/// Adam optimizer.
+/// The state of each parameter is its first and second moment estimate.
+#[derive(Clone, Copy)]
+pub struct Adam<T: Float> {
+    pub lr: T,
+    pub beta1: T,
+    pub beta2: T,
+    pub epsilon: T,
+    t: i32,
+}
+
+impl<T: Float> Adam<T> {
+    pub fn new(lr: T) -> Self {
+        Self {
+            lr,
+            beta1: num!(0.9),
+            beta2: num!(0.999),
+            epsilon: num!(1e-8),
+            t: 0,
+        }
+    }
+}
+
+/// Adam with decoupled weight decay.
+/// The decay is applied directly to the parameters, and not through the gradient.
+#[derive(Clone, Copy)]
+pub struct AdamW<T: Float> {
+    pub adam: Adam<T>,
+    pub weight_decay: T,
+}
+
+impl<T: Float> AdamW<T> {
+    pub fn new(lr: T, weight_decay: T) -> Self {
+        Self {
+            adam: Adam::new(lr),
+            weight_decay,
+        }
+    }
+}
+
+macro_rules! impl_adam {
+    ($T: ty) => {
+        impl Optimizer<$T> for Adam<$T> {
+            type State = ($T, $T);
+
+            fn lr(&self) -> $T {
+                self.lr
+            }
+
+            fn set_lr(&mut self, lr: $T) {
+                self.lr = lr
+            }
+
+            fn step(&mut self) {
+                self.t += 1;
+            }
+
+            fn update(&mut self, params: &mut [$T], gradient: &[$T], state: &mut [($T, $T)]) {
+                let m_correction = 1. - self.beta1.powi(self.t);
+                let v_correction = 1. - self.beta2.powi(self.t);
+
+                for ((p, g), (m, v)) in params.iter_mut().zip(gradient).zip(state) {
+                    *m = self.beta1 * *m + (1. - self.beta1) * g;
+                    *v = self.beta2 * *v + (1. - self.beta2) * g * g;
+
+                    let m = *m / m_correction;
+                    let v = *v / v_correction;
+                    *p -= self.lr * m / (v.sqrt() + self.epsilon);
+                }
+            }
+        }
+
+        impl Serialization for Adam<$T> {
+            fn serialize_into(&self, writer: &mut dyn Write) -> Result<()> {
+                writer.write_all(&self.t.to_le_bytes())?;
+                Ok(())
+            }
+
+            fn deserialize_from(&mut self, reader: &mut dyn Read) -> Result<()> {
+                let mut bytes = [0u8; 4];
+                reader.read_exact(&mut bytes)?;
+                self.t = i32::from_le_bytes(bytes);
+                Ok(())
+            }
+        }
+
+        impl Optimizer<$T> for AdamW<$T> {
+            type State = ($T, $T);
+
+            fn lr(&self) -> $T {
+                self.adam.lr
+            }
+
+            fn set_lr(&mut self, lr: $T) {
+                self.adam.lr = lr
+            }
+
+            fn step(&mut self) {
+                self.adam.step()
+            }
+
+            fn update(&mut self, params: &mut [$T], gradient: &[$T], state: &mut [($T, $T)]) {
+                for p in params.iter_mut() {
+                    *p -= self.adam.lr * self.weight_decay * *p;
+                }
+                self.adam.update(params, gradient, state)
+            }
+        }
+
+        impl Serialization for AdamW<$T> {
+            fn serialize_into(&self, writer: &mut dyn Write) -> Result<()> {
+                self.adam.serialize_into(writer)
+            }
+
+            fn deserialize_from(&mut self, reader: &mut dyn Read) -> Result<()> {
+                self.adam.deserialize_from(reader)
+            }
+        }
+    };
+}
+
+impl_adam!(f32);
+impl_adam!(f64);
+
+/// RMSProp optimizer.
+/// The state of each parameter is a moving average of its squared gradient.
+#[derive(Clone, Copy)]
+pub struct RmsProp<T: Float> {
+    pub lr: T,
+    pub rho: T,
+    pub epsilon: T,
+}
+
+impl<T: Float> RmsProp<T> {
+    pub fn new(lr: T) -> Self {
+        Self {
+            lr,
+            rho: num!(0.9),
+            epsilon: num!(1e-8),
+        }
+    }
+}
+
+/// AdaGrad optimizer.
+/// The state of each parameter is the sum of its squared gradients.
+#[derive(Clone, Copy)]
+pub struct AdaGrad<T: Float> {
+    pub lr: T,
+    pub epsilon: T,
+}
+
+impl<T: Float> AdaGrad<T> {
+    pub fn new(lr: T) -> Self {
+        Self {
+            lr,
+            epsilon: num!(1e-8),
+        }
+    }
+}
+
+/// AdaDelta optimizer.
+/// The state of each parameter is a moving average of its squared gradient and of its squared updates.
+#[derive(Clone, Copy)]
+pub struct AdaDelta<T: Float> {
+    pub lr: T,
+    pub rho: T,
+    pub epsilon: T,
+}
+
+impl<T: Float> AdaDelta<T> {
+    pub fn new(lr: T) -> Self {
+        Self {
+            lr,
+            rho: num!(0.95),
+            epsilon: num!(1e-6),
+        }
+    }
+}
+
+macro_rules! impl_adaptive {
+    ($T: ty) => {
+        impl Optimizer<$T> for RmsProp<$T> {
+            type State = $T;
+
+            fn lr(&self) -> $T {
+                self.lr
+            }
+
+            fn set_lr(&mut self, lr: $T) {
+                self.lr = lr
+            }
+
+            fn update(&mut self, params: &mut [$T], gradient: &[$T], state: &mut [$T]) {
+                for ((p, g), s) in params.iter_mut().zip(gradient).zip(state) {
+                    *s = self.rho * *s + (1. - self.rho) * g * g;
+                    *p -= self.lr * g / (s.sqrt() + self.epsilon);
+                }
+            }
+        }
+
+        impl Optimizer<$T> for AdaGrad<$T> {
+            type State = $T;
+
+            fn lr(&self) -> $T {
+                self.lr
+            }
+
+            fn set_lr(&mut self, lr: $T) {
+                self.lr = lr
+            }
+
+            fn update(&mut self, params: &mut [$T], gradient: &[$T], state: &mut [$T]) {
+                for ((p, g), s) in params.iter_mut().zip(gradient).zip(state) {
+                    *s += g * g;
+                    *p -= self.lr * g / (s.sqrt() + self.epsilon);
+                }
+            }
+        }
+
+        impl Optimizer<$T> for AdaDelta<$T> {
+            type State = ($T, $T);
+
+            fn lr(&self) -> $T {
+                self.lr
+            }
+
+            fn set_lr(&mut self, lr: $T) {
+                self.lr = lr
+            }
+
+            fn update(&mut self, params: &mut [$T], gradient: &[$T], state: &mut [($T, $T)]) {
+                for ((p, g), (s, d)) in params.iter_mut().zip(gradient).zip(state) {
+                    *s = self.rho * *s + (1. - self.rho) * g * g;
+                    let delta = (*d + self.epsilon).sqrt() / (*s + self.epsilon).sqrt() * g;
+                    *d = self.rho * *d + (1. - self.rho) * delta * delta;
+                    *p -= self.lr * delta;
+                }
+            }
+        }
+    };
+}
+
+impl_adaptive!(f32);
+impl_adaptive!(f64);
+
+/// For optimizers where all state is stored per parameter.
+macro_rules! impl_stateless_serialization {
+    ($($name: ident),*) => {$(
+        impl<T: Float> Serialization for $name<T> {
+            fn serialize_into(&self, _: &mut dyn Write) -> Result<()> {
+                Ok(())
+            }
+
+            fn deserialize_from(&mut self, _: &mut dyn Read) -> Result<()> {
+                Ok(())
+            }
+        }
+    )*};
+}
+
+impl_stateless_serialization!(Sgd, Momentum, Nesterov, RmsProp, AdaGrad, AdaDelta);
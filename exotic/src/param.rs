@@ -0,0 +1,88 @@
+use crate::{optimizer::Optimizer, *};
+
+/// A trainable tensor of a layer, with its accumulated gradient and the optimizer state of every element.
+/// `V` is the storage of the values, like `[T; LEN]` or `Vec<T>`, and `S` that of the states.
+///
+/// The layers that own parameters keep their optimizer, and update all of their tensors with it.
+#[derive(Clone, Copy)]
+pub struct Param<V, S> {
+    pub value: V,
+    pub gradient: V,
+    pub state: S,
+}
+
+impl<V, S> Param<V, S> {
+    /// Apply the gradient with `optimizer`, and reset it to zero.
+    pub fn update<T: Float, Opt: Optimizer<T>>(&mut self, optimizer: &mut Opt)
+    where
+        V: AsRef<[T]> + AsMut<[T]>,
+        S: AsMut<[Opt::State]>,
+    {
+        optimizer.update(
+            self.value.as_mut(),
+            self.gradient.as_ref(),
+            self.state.as_mut(),
+        );
+        self.zero_grad();
+    }
+
+    pub fn zero_grad<T: Float>(&mut self)
+    where
+        V: AsMut<[T]>,
+    {
+        self.gradient.as_mut().iter_mut().for_each(|g| *g = T::_0);
+    }
+
+    /// Add the gradient of the same tensor in another copy of the layer.
+    /// Useful for reducing the gradients of data-parallel replicas, before calling `apply_gradients`.
+    pub fn add_gradients<T: Float>(&mut self, other: &Self)
+    where
+        V: AsRef<[T]> + AsMut<[T]>,
+    {
+        for (g, &o) in self
+            .gradient
+            .as_mut()
+            .iter_mut()
+            .zip(other.gradient.as_ref())
+        {
+            *g = *g + o;
+        }
+    }
+}
+
+/// The `Layer` methods for training a layer made of `Param`s,
+/// with an `optimizer` field, and `accumulate` and `frozen` flags.
+/// `backpropagate` should only accumulate the gradients when the layer isn't frozen,
+/// and then call `apply_gradients`, unless the layer is accumulating.
+macro_rules! impl_training {
+    ($T: ty; $($param: ident),+) => {
+        fn set_lr(&mut self, lr: $T) {
+            self.optimizer.set_lr(lr)
+        }
+
+        fn set_accumulate(&mut self, accumulate: bool) {
+            self.accumulate = accumulate
+        }
+
+        fn accumulating(&self) -> bool {
+            self.accumulate
+        }
+
+        fn apply_gradients(&mut self) {
+            if !self.frozen {
+                self.optimizer.step();
+                $(self.$param.update(&mut self.optimizer);)+
+            }
+        }
+
+        fn set_frozen(&mut self, frozen: bool) {
+            self.frozen = frozen
+        }
+
+        fn zero_grad(&mut self) {
+            $(self.$param.zero_grad();)+
+        }
+    };
+}
+
+pub(crate) use impl_training;
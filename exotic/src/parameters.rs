@@ -0,0 +1,21 @@
+use crate::*;
+
+/// Access to the trainable parameters of a layer, one named tensor at a time.
+/// The parameters of a `model!` network marked `#[parameters]` are named by their path, like `l0.weights`.
+///
+/// The default implementation is for layers without parameters.
+/// ```ignore
+/// let mut l2 = 0.;
+/// net.visit_params(&mut |_, params| l2 += params.iter().map(|p| p * p).sum::<f32>());
+///
+/// net.visit_params(&mut |name, params| println!("{name}: {}", params.len()));
+/// ```
+pub trait Parameters<T: Float> {
+    /// The number of trainable parameters.
+    const PARAM_COUNT: usize = 0;
+
+    fn visit_params(&self, _visitor: &mut impl FnMut(&str, &[T])) {}
+    fn visit_params_mut(&mut self, _visitor: &mut impl FnMut(&str, &mut [T])) {}
+    /// Visit the accumulated gradients, in the same order and with the same names as the parameters.
+    fn visit_grads(&self, _visitor: &mut impl FnMut(&str, &[T])) {}
+}
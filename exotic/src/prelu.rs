@@ -0,0 +1,144 @@
+use crate::{
+    optimizer::*,
+    param::{impl_training, Param},
+    parameters::Parameters,
+    serialization::Serialization,
+    *,
+};
+
+/// Parametric ReLU, with a trainable slope for the negative part of every input element.
+///
+/// Unlike the layers from `activation!`, the slopes are updated by `backpropagate`,
+/// through the same optimizer path as `DenseLayer`.
+#[derive(Clone, Copy)]
+pub struct Prelu<T: Float, const LEN: usize, Opt: Optimizer<T> = Sgd<T>> {
+    pub alpha: Param<[T; LEN], [Opt::State; LEN]>,
+    pub optimizer: Opt,
+    accumulate: bool,
+    frozen: bool,
+}
+
+impl<T: Float + PartialOrd, const LEN: usize, Opt: Optimizer<T>> Prelu<T, LEN, Opt> {
+    /// All slopes start at 0.25.
+    pub fn new(optimizer: Opt) -> Self {
+        Self {
+            alpha: Param {
+                value: [num!(0.25); LEN],
+                gradient: [T::_0; LEN],
+                state: [Opt::State::default(); LEN],
+            },
+            optimizer,
+            accumulate: false,
+            frozen: false,
+        }
+    }
+
+    /// Add the gradient of the slopes to `alpha.gradient` (scaled by `scale`) unless the layer is frozen,
+    /// and return the gradient of the input.
+    fn accumulate_gradients(&mut self, i: &[T; LEN], gradient: &[T; LEN], scale: T) -> [T; LEN] {
+        let mut buffer = [T::_0; LEN];
+        for n in 0..LEN {
+            if i[n] > T::_0 {
+                buffer[n] = gradient[n];
+            } else {
+                buffer[n] = self.alpha.value[n] * gradient[n];
+                if !self.frozen {
+                    self.alpha.gradient[n] = self.alpha.gradient[n] + gradient[n] * i[n] * scale;
+                }
+            }
+        }
+        buffer
+    }
+
+    /// Add the accumulated gradients of another copy of the layer to the gradients of this one.
+    pub fn add_gradients(&mut self, other: &Self) {
+        self.alpha.add_gradients(&other.alpha);
+    }
+}
+
+impl<T: Float + PartialOrd, const LEN: usize, Opt: Optimizer<T>> Layer<T, LEN, LEN, LEN>
+    for Prelu<T, LEN, Opt>
+{
+    type Gradient = [T; LEN];
+
+    fn predict(
+        &mut self,
+        i: impl StaticVec<T, LEN>,
+        buffer: &mut impl StaticVec<T, LEN>,
+    ) -> Result<()> {
+        let i = i.moo_ref();
+        let buffer = buffer.mut_moo_ref();
+        for n in 0..LEN {
+            buffer[n] = if i[n] > T::_0 {
+                i[n]
+            } else {
+                self.alpha.value[n] * i[n]
+            };
+        }
+        Ok(())
+    }
+
+    /// Here buffer is shadowed, so a NullVec can safely be passed.
+    fn backpropagate(
+        &mut self,
+        i: impl StaticVec<T, LEN>,
+        _buffer: &impl StaticVec<T, LEN>,
+        gradient: impl StaticVec<T, LEN>,
+    ) -> Result<[T; LEN]> {
+        let buffer = self.accumulate_gradients(i.moo_ref(), gradient.moo_ref(), T::_1);
+        if !self.accumulate {
+            self.apply_gradients();
+        }
+        Ok(buffer)
+    }
+
+    impl_training!(T; alpha);
+}
+
+impl<T: Float + PartialOrd, const LEN: usize, Opt: Optimizer<T>> SampleLayer<T, LEN, LEN>
+    for Prelu<T, LEN, Opt>
+{
+    fn backpropagate_sample(
+        &mut self,
+        i: &[T; LEN],
+        _o: &[T; LEN],
+        gradient: &[T; LEN],
+        scale: T,
+    ) -> Result<[T; LEN]> {
+        Ok(self.accumulate_gradients(i, gradient, scale))
+    }
+}
+
+crate::sample_batch_layer!([T: Float + PartialOrd, const LEN: usize, Opt: Optimizer<T>,] T, LEN, LEN, Prelu<T, LEN, Opt>);
+
+impl<T: Float + Serialization, const LEN: usize, Opt: Optimizer<T>> Serialization
+    for Prelu<T, LEN, Opt>
+{
+    fn serialize_into(&self, writer: &mut dyn std::io::Write) -> Result<()> {
+        self.alpha.value.serialize_into(writer)?;
+        self.alpha.state.serialize_into(writer)?;
+        self.optimizer.serialize_into(writer)
+    }
+
+    fn deserialize_from(&mut self, reader: &mut dyn std::io::Read) -> Result<()> {
+        self.alpha.value.deserialize_from(reader)?;
+        self.alpha.state.deserialize_from(reader)?;
+        self.optimizer.deserialize_from(reader)
+    }
+}
+
+impl<T: Float, const LEN: usize, Opt: Optimizer<T>> Parameters<T> for Prelu<T, LEN, Opt> {
+    const PARAM_COUNT: usize = LEN;
+
+    fn visit_params(&self, visitor: &mut impl FnMut(&str, &[T])) {
+        visitor("alpha", &self.alpha.value);
+    }
+
+    fn visit_params_mut(&mut self, visitor: &mut impl FnMut(&str, &mut [T])) {
+        visitor("alpha", &mut self.alpha.value);
+    }
+
+    fn visit_grads(&self, visitor: &mut impl FnMut(&str, &[T])) {
+        visitor("alpha", &self.alpha.gradient);
+    }
+}
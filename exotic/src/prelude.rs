@@ -1,3 +1,7 @@
-pub use crate::{activation::*, dense::*, onehot, random, slas::prelude::*, Layer};
+pub use crate::{
+    activation::*, dense::*, dual::Dual, gradcheck::*, loss::*, maxout::*, onehot, optimizer::*,
+    param::Param, parameters::*, prelu::*, random, scheduler::*, serialization::*,
+    slas::prelude::*, transpose::*, BatchLayer, Layer, LayerOutput, SampleLayer,
+};
 pub use anyhow::*;
 pub use slas::prelude::*;
@@ -0,0 +1,248 @@
+use crate::*;
+use std::f64::consts::PI;
+
+/// Learning rate schedule.
+///
+/// The learning rate returned by `step` can be applied to all layers of a model at once, with `Layer::set_lr`.
+/// ```ignore
+/// let mut scheduler = Exponential::new(0.01, 0.99999);
+/// for epoch in 0..400000 {
+///     net.set_lr(scheduler.step());
+///     ...
+/// }
+/// ```
+pub trait Scheduler<T: Float> {
+    /// Advance the schedule by one step, and return the learning rate to use for it.
+    fn step(&mut self) -> T;
+}
+
+/// Multiply the learning rate by `gamma` every `step_size` steps.
+#[derive(Clone, Copy)]
+pub struct StepDecay {
+    pub lr: f64,
+    pub gamma: f64,
+    pub step_size: usize,
+    t: usize,
+}
+
+impl StepDecay {
+    /// Panics if `step_size` is 0.
+    pub fn new(lr: f64, gamma: f64, step_size: usize) -> Self {
+        assert!(
+            step_size > 0,
+            "the step size of StepDecay must be at least 1"
+        );
+        Self {
+            lr,
+            gamma,
+            step_size,
+            t: 0,
+        }
+    }
+}
+
+impl<T: Float> Scheduler<T> for StepDecay {
+    fn step(&mut self) -> T {
+        let lr = self.lr * self.gamma.powf((self.t / self.step_size) as f64);
+        self.t += 1;
+        T::from_f64(lr)
+    }
+}
+
+/// Multiply the learning rate by `gamma` every step.
+#[derive(Clone, Copy)]
+pub struct Exponential {
+    pub lr: f64,
+    pub gamma: f64,
+    t: usize,
+}
+
+impl Exponential {
+    pub fn new(lr: f64, gamma: f64) -> Self {
+        Self { lr, gamma, t: 0 }
+    }
+}
+
+impl<T: Float> Scheduler<T> for Exponential {
+    fn step(&mut self) -> T {
+        let lr = self.lr * self.gamma.powf(self.t as f64);
+        self.t += 1;
+        T::from_f64(lr)
+    }
+}
+
+/// Cosine annealing with warm restarts (SGDR).
+///
+/// The learning rate follows half a cosine from `max_lr` to `min_lr` over `period` steps,
+/// after which it restarts at `max_lr`, and the period is multiplied by `period_mult`.
+#[derive(Clone, Copy)]
+pub struct CosineAnnealing {
+    pub max_lr: f64,
+    pub min_lr: f64,
+    pub period: usize,
+    pub period_mult: usize,
+    t: usize,
+}
+
+impl CosineAnnealing {
+    /// Panics if `period` or `period_mult` is 0.
+    pub fn new(max_lr: f64, min_lr: f64, period: usize, period_mult: usize) -> Self {
+        assert!(
+            period > 0,
+            "the period of CosineAnnealing must be at least 1"
+        );
+        assert!(
+            period_mult > 0,
+            "the period multiplier of CosineAnnealing must be at least 1"
+        );
+        Self {
+            max_lr,
+            min_lr,
+            period,
+            period_mult,
+            t: 0,
+        }
+    }
+}
+
+impl<T: Float> Scheduler<T> for CosineAnnealing {
+    fn step(&mut self) -> T {
+        if self.t >= self.period {
+            self.t = 0;
+            self.period *= self.period_mult;
+        }
+        let progress = self.t as f64 / self.period as f64;
+        self.t += 1;
+        T::from_f64(self.min_lr + (self.max_lr - self.min_lr) * (1. + (PI * progress).cos()) / 2.)
+    }
+}
+
+/// Linearly ramp up the learning rate of another schedule over the first `warmup` steps.
+#[derive(Clone, Copy)]
+pub struct LinearWarmup<S> {
+    pub scheduler: S,
+    pub warmup: usize,
+    t: usize,
+}
+
+impl<S> LinearWarmup<S> {
+    pub fn new(scheduler: S, warmup: usize) -> Self {
+        Self {
+            scheduler,
+            warmup,
+            t: 0,
+        }
+    }
+}
+
+impl<T: Float, S: Scheduler<T>> Scheduler<T> for LinearWarmup<S> {
+    fn step(&mut self) -> T {
+        let lr = self.scheduler.step();
+        if self.t >= self.warmup {
+            return lr;
+        }
+        self.t += 1;
+        lr * T::from_f64(self.t as f64 / self.warmup as f64)
+    }
+}
+
+/// One-cycle policy.
+///
+/// The learning rate is annealed from `max_lr / div_factor` up to `max_lr` over the first `pct_start` of `total_steps`,
+/// and then down to `max_lr / (div_factor * final_div_factor)` over the rest.
+#[derive(Clone, Copy)]
+pub struct OneCycle {
+    pub max_lr: f64,
+    pub total_steps: usize,
+    pub pct_start: f64,
+    pub div_factor: f64,
+    pub final_div_factor: f64,
+    t: usize,
+}
+
+impl OneCycle {
+    pub fn new(max_lr: f64, total_steps: usize) -> Self {
+        Self {
+            max_lr,
+            total_steps,
+            pct_start: 0.3,
+            div_factor: 25.,
+            final_div_factor: 1e4,
+            t: 0,
+        }
+    }
+}
+
+impl<T: Float> Scheduler<T> for OneCycle {
+    fn step(&mut self) -> T {
+        let anneal = |from: f64, to: f64, progress: f64| {
+            to + (from - to) * (1. + (PI * progress.min(1.)).cos()) / 2.
+        };
+
+        let initial_lr = self.max_lr / self.div_factor;
+        let final_lr = initial_lr / self.final_div_factor;
+        let warmup = (self.total_steps as f64 * self.pct_start).max(1.);
+        let t = self.t as f64;
+        self.t += 1;
+
+        T::from_f64(if t < warmup {
+            anneal(initial_lr, self.max_lr, t / warmup)
+        } else {
+            anneal(
+                self.max_lr,
+                final_lr,
+                (t - warmup) / (self.total_steps as f64 - warmup).max(1.),
+            )
+        })
+    }
+}
+
+/// Reduce the learning rate by `factor`, when a metric has stopped improving for `patience` observations.
+///
+/// The metric (e.g. a validation loss, which should be minimized) is reported with `observe`.
+#[derive(Clone, Copy)]
+pub struct ReduceOnPlateau {
+    pub lr: f64,
+    pub factor: f64,
+    pub patience: usize,
+    /// Minimum relative improvement of the metric, to count as an improvement.
+    pub threshold: f64,
+    pub min_lr: f64,
+    best: f64,
+    bad_observations: usize,
+}
+
+impl ReduceOnPlateau {
+    pub fn new(lr: f64, factor: f64, patience: usize) -> Self {
+        Self {
+            lr,
+            factor,
+            patience,
+            threshold: 1e-4,
+            min_lr: 0.,
+            best: f64::INFINITY,
+            bad_observations: 0,
+        }
+    }
+
+    pub fn observe(&mut self, metric: impl Into<f64>) {
+        let metric = metric.into();
+        if metric < self.best * (1. - self.threshold) {
+            self.best = metric;
+            self.bad_observations = 0;
+            return;
+        }
+
+        self.bad_observations += 1;
+        if self.bad_observations > self.patience {
+            self.lr = (self.lr * self.factor).max(self.min_lr);
+            self.bad_observations = 0;
+        }
+    }
+}
+
+impl<T: Float> Scheduler<T> for ReduceOnPlateau {
+    fn step(&mut self) -> T {
+        T::from_f64(self.lr)
+    }
+}
@@ -0,0 +1,191 @@
+use crate::*;
+use std::io::{Read, Write};
+
+/// Binary (little-endian) serialization of layer parameters and optimizer state.
+pub trait Serialization {
+    fn serialize_into(&self, writer: &mut dyn Write) -> Result<()>;
+    fn deserialize_from(&mut self, reader: &mut dyn Read) -> Result<()>;
+}
+
+macro_rules! impl_serialization {
+    ($T: ty) => {
+        impl Serialization for $T {
+            fn serialize_into(&self, writer: &mut dyn Write) -> Result<()> {
+                writer.write_all(&self.to_le_bytes())?;
+                Ok(())
+            }
+
+            fn deserialize_from(&mut self, reader: &mut dyn Read) -> Result<()> {
+                let mut bytes = [0u8; std::mem::size_of::<$T>()];
+                reader.read_exact(&mut bytes)?;
+                *self = <$T>::from_le_bytes(bytes);
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_serialization!(f32);
+impl_serialization!(f64);
+
+impl Serialization for () {
+    fn serialize_into(&self, _: &mut dyn Write) -> Result<()> {
+        Ok(())
+    }
+
+    fn deserialize_from(&mut self, _: &mut dyn Read) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<A: Serialization, B: Serialization> Serialization for (A, B) {
+    fn serialize_into(&self, writer: &mut dyn Write) -> Result<()> {
+        self.0.serialize_into(writer)?;
+        self.1.serialize_into(writer)
+    }
+
+    fn deserialize_from(&mut self, reader: &mut dyn Read) -> Result<()> {
+        self.0.deserialize_from(reader)?;
+        self.1.deserialize_from(reader)
+    }
+}
+
+impl<S: Serialization> Serialization for [S] {
+    fn serialize_into(&self, writer: &mut dyn Write) -> Result<()> {
+        for n in self {
+            n.serialize_into(writer)?;
+        }
+        Ok(())
+    }
+
+    fn deserialize_from(&mut self, reader: &mut dyn Read) -> Result<()> {
+        for n in self {
+            n.deserialize_from(reader)?;
+        }
+        Ok(())
+    }
+}
+
+/// The version of the format written by [`save`].
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"EXOT";
+
+/// The shape of a layer, as recorded in the header of a saved model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerShape {
+    pub name: &'static str,
+    pub i_len: usize,
+    pub o_len: usize,
+    pub param_count: usize,
+}
+
+/// Write a model, with a header containing the format version, the float type and the shape of every layer.
+/// This is what the `save` method of `model!` networks marked `#[serialize]` uses.
+///
+/// All numbers are little-endian. The header is the magic bytes `EXOT`, the version as a `u32`,
+/// the size of the float type as a `u8`, the number of layers as a `u32`,
+/// and for every layer its name (a `u32` length and utf-8) followed by its input length, output length
+/// and parameter count as `u64`s. After it, the length of the body as a `u64`,
+/// and the body written by [`Serialization::serialize_into`].
+pub fn save<T: Float>(
+    model: &impl Serialization,
+    layers: &[LayerShape],
+    writer: &mut impl Write,
+) -> Result<()> {
+    let mut body = vec![];
+    model.serialize_into(&mut body)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&[std::mem::size_of::<T>() as u8])?;
+    writer.write_all(&(layers.len() as u32).to_le_bytes())?;
+    for layer in layers {
+        writer.write_all(&(layer.name.len() as u32).to_le_bytes())?;
+        writer.write_all(layer.name.as_bytes())?;
+        for n in [layer.i_len, layer.o_len, layer.param_count] {
+            writer.write_all(&(n as u64).to_le_bytes())?;
+        }
+    }
+    writer.write_all(&(body.len() as u64).to_le_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+/// Read a model written by [`save`].
+/// Fails without changing the model if the header doesn't match its layers.
+pub fn load<T: Float>(
+    model: &mut impl Serialization,
+    layers: &[LayerShape],
+    reader: &mut impl Read,
+) -> Result<()> {
+    ensure!(&read_bytes::<4>(reader)? == MAGIC, "not a saved model");
+
+    let version = u32::from_le_bytes(read_bytes(reader)?);
+    ensure!(
+        version == FORMAT_VERSION,
+        "the model was saved with format version {version}, but only version {FORMAT_VERSION} can be loaded"
+    );
+
+    let [float_size] = read_bytes(reader)?;
+    let expected_size = std::mem::size_of::<T>();
+    ensure!(
+        float_size as usize == expected_size,
+        "the model was saved with {}-bit floats, but uses {}-bit floats",
+        float_size as usize * 8,
+        expected_size * 8
+    );
+
+    let layer_count = u32::from_le_bytes(read_bytes(reader)?) as usize;
+    ensure!(
+        layer_count == layers.len(),
+        "the model was saved with {layer_count} layers, but has {}",
+        layers.len()
+    );
+
+    for layer in layers {
+        // The length is checked before reading the name, so a corrupt header can't cause a huge allocation.
+        let name_len = u32::from_le_bytes(read_bytes(reader)?) as usize;
+        ensure!(
+            name_len == layer.name.len(),
+            "the model was saved with a layer name of {name_len} bytes where it has layer `{}`",
+            layer.name
+        );
+        let mut name = vec![0; name_len];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name)?;
+        let i_len = u64::from_le_bytes(read_bytes(reader)?) as usize;
+        let o_len = u64::from_le_bytes(read_bytes(reader)?) as usize;
+        let param_count = u64::from_le_bytes(read_bytes(reader)?) as usize;
+
+        ensure!(
+            name == layer.name
+                && [i_len, o_len, param_count] == [layer.i_len, layer.o_len, layer.param_count],
+            "the model was saved with layer `{name}` of {i_len} inputs, {o_len} outputs and {param_count} parameters, \
+            but has layer `{}` of {} inputs, {} outputs and {} parameters",
+            layer.name,
+            layer.i_len,
+            layer.o_len,
+            layer.param_count
+        );
+    }
+
+    let body_len = u64::from_le_bytes(read_bytes(reader)?) as usize;
+    let mut expected = vec![];
+    model.serialize_into(&mut expected)?;
+    ensure!(
+        body_len == expected.len(),
+        "the model was saved in {body_len} bytes, but needs {}, it might use different optimizers",
+        expected.len()
+    );
+
+    let mut body = vec![0; body_len];
+    reader.read_exact(&mut body)?;
+    model.deserialize_from(&mut body.as_slice())
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
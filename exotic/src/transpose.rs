@@ -0,0 +1,100 @@
+use crate::*;
+
+/// Layers whose parameters can also be used in the opposite direction, from an output to an input.
+/// This is used for tied weights, like an autoencoder whose decoder uses the transposed weights of its encoder.
+/// The gradients of the parameters are added to those of the layer, so they are applied together with its own.
+pub trait Transpose<T: Float, const I_LEN: usize, const O_LEN: usize> {
+    fn predict_transposed(
+        &self,
+        i: impl StaticVec<T, O_LEN>,
+        buffer: &mut impl StaticVec<T, I_LEN>,
+    ) -> Result<()>;
+    fn backpropagate_transposed(
+        &mut self,
+        i: impl StaticVec<T, O_LEN>,
+        gradient: impl StaticVec<T, I_LEN>,
+    ) -> Result<[T; O_LEN]>;
+
+    fn predict_batch_transposed<const BATCH: usize>(
+        &self,
+        i: &[[T; O_LEN]; BATCH],
+        buffer: &mut [[T; I_LEN]; BATCH],
+    ) -> Result<()>;
+    fn backpropagate_batch_transposed<const BATCH: usize>(
+        &mut self,
+        i: &[[T; O_LEN]; BATCH],
+        gradient: &[[T; I_LEN]; BATCH],
+    ) -> Result<[[T; O_LEN]; BATCH]>;
+}
+
+/// A layer used in the opposite direction, with the same parameters.
+/// ```ignore
+/// let mut encoder = DenseLayer::<f32, Blas, 4, 2>::random(Sgd::new(0.1));
+/// let mut code = [0.; 2];
+/// let mut decoded = [0.; 4];
+/// encoder.predict(&i, &mut code)?;
+/// Transposed(&mut encoder).predict(&code, &mut decoded)?;
+/// ```
+/// Learning rate, accumulation and updates are left to the layer itself,
+/// so the gradients of both directions are applied by the same update.
+pub struct Transposed<'a, L>(pub &'a mut L);
+
+impl<'a, T: Float, L: Transpose<T, I_LEN, O_LEN>, const I_LEN: usize, const O_LEN: usize>
+    Layer<T, O_LEN, I_LEN, I_LEN> for Transposed<'a, L>
+{
+    type Gradient = [T; O_LEN];
+
+    fn predict(
+        &mut self,
+        i: impl StaticVec<T, O_LEN>,
+        buffer: &mut impl StaticVec<T, I_LEN>,
+    ) -> Result<()> {
+        self.0.predict_transposed(i, buffer)
+    }
+
+    fn backpropagate(
+        &mut self,
+        i: impl StaticVec<T, O_LEN>,
+        _buffer: &impl StaticVec<T, I_LEN>,
+        gradient: impl StaticVec<T, I_LEN>,
+    ) -> Result<[T; O_LEN]> {
+        self.0.backpropagate_transposed(i, gradient)
+    }
+}
+
+impl<
+        'a,
+        T: Float,
+        L: Transpose<T, I_LEN, O_LEN>,
+        const I_LEN: usize,
+        const O_LEN: usize,
+        const BATCH: usize,
+    > BatchLayer<T, O_LEN, I_LEN, BATCH> for Transposed<'a, L>
+{
+    type BatchBuffer = [[T; I_LEN]; BATCH];
+
+    fn batch_buffer() -> Self::BatchBuffer {
+        [[T::_0; I_LEN]; BATCH]
+    }
+
+    fn batch_output(buffer: &Self::BatchBuffer) -> &[[T; I_LEN]; BATCH] {
+        buffer
+    }
+
+    fn predict_batch(
+        &mut self,
+        i: &[[T; O_LEN]; BATCH],
+        buffer: &mut Self::BatchBuffer,
+    ) -> Result<()> {
+        self.0.predict_batch_transposed(i, buffer)
+    }
+
+    fn backpropagate_batch(
+        &mut self,
+        i: &[[T; O_LEN]; BATCH],
+        _buffer: &Self::BatchBuffer,
+        gradient: &[[T; I_LEN]; BATCH],
+    ) -> Result<[[T; O_LEN]; BATCH]> {
+        self.0.backpropagate_batch_transposed(i, gradient)
+    }
+}
@@ -0,0 +1,173 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::*;
+use syn::{BinOp, Expr, Ident};
+
+/// A vector that can be used as (part of) the input of a layer.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Node {
+    /// The whole input of the model.
+    Input,
+    /// One of the named inputs of the model, which are slices of its input.
+    NamedInput(usize),
+    /// The output of the layer with this index.
+    Layer(usize),
+}
+
+/// An element-wise combination of the outputs of nodes with the same length.
+pub enum Merge {
+    Node(Node),
+    Add(Box<Merge>, Box<Merge>),
+    Mul(Box<Merge>, Box<Merge>),
+}
+
+/// Where the input of a layer comes from.
+pub enum LayerInput {
+    /// The output of the previous layer, or the input of the model for the first layer.
+    Previous,
+    /// The concatenation of a list of merges, written as `l3(l2 + l0, input)`.
+    Concat(Vec<Merge>),
+}
+
+impl Merge {
+    pub fn parse(expr: &Expr, resolve: &impl Fn(&Ident) -> syn::Result<Node>) -> syn::Result<Self> {
+        match expr {
+            Expr::Path(path) if path.path.get_ident().is_some() => {
+                Ok(Merge::Node(resolve(path.path.get_ident().unwrap())?))
+            }
+            Expr::Binary(binary) if matches!(binary.op, BinOp::Add(_) | BinOp::Mul(_)) => {
+                let (a, b) = (
+                    Box::new(Self::parse(&binary.left, resolve)?),
+                    Box::new(Self::parse(&binary.right, resolve)?),
+                );
+                Ok(match binary.op {
+                    BinOp::Add(_) => Merge::Add(a, b),
+                    _ => Merge::Mul(a, b),
+                })
+            }
+            Expr::Paren(paren) => Self::parse(&paren.expr, resolve),
+            _ => Err(syn::Error::new_spanned(
+                expr,
+                "expected the name of a layer or an input, or a sum or product of them",
+            )),
+        }
+    }
+
+    /// Every node used in the merge, from left to right.
+    pub fn nodes(&self) -> Vec<Node> {
+        match self {
+            Merge::Node(node) => vec![*node],
+            Merge::Add(a, b) | Merge::Mul(a, b) => [a.nodes(), b.nodes()].concat(),
+        }
+    }
+
+    /// Expression for element `j` of the merge.
+    /// `access` gives an indexable expression for the output of a node.
+    pub fn element(
+        &self,
+        access: &impl Fn(Node) -> TokenStream2,
+        j: &TokenStream2,
+    ) -> TokenStream2 {
+        match self {
+            Merge::Node(node) => {
+                let node = access(*node);
+                quote! {#node[#j]}
+            }
+            Merge::Add(a, b) => {
+                let (a, b) = (a.element(access, j), b.element(access, j));
+                quote! {#a + #b}
+            }
+            Merge::Mul(a, b) => {
+                let (a, b) = (a.factor(access, j), b.factor(access, j));
+                quote! {#a * #b}
+            }
+        }
+    }
+
+    /// Like `element`, but with parentheses around sums, so it can be multiplied.
+    fn factor(&self, access: &impl Fn(Node) -> TokenStream2, j: &TokenStream2) -> TokenStream2 {
+        let element = self.element(access, j);
+        match self {
+            Merge::Add(..) => quote! {(#element)},
+            _ => element,
+        }
+    }
+
+    /// The partial derivative of element `j` of the merge, with respect to element `j` of every node in it,
+    /// multiplied by `scale`. A derivative of `None` means one.
+    pub fn partials(
+        &self,
+        access: &impl Fn(Node) -> TokenStream2,
+        j: &TokenStream2,
+        scale: Option<TokenStream2>,
+    ) -> Vec<(Node, Option<TokenStream2>)> {
+        let scaled = |factor: TokenStream2| match &scale {
+            Some(scale) => Some(quote! {#scale * #factor}),
+            None => Some(factor),
+        };
+        match self {
+            Merge::Node(node) => vec![(*node, scale)],
+            Merge::Add(a, b) => [
+                a.partials(access, j, scale.clone()),
+                b.partials(access, j, scale),
+            ]
+            .concat(),
+            Merge::Mul(a, b) => [
+                a.partials(access, j, scaled(b.factor(access, j))),
+                b.partials(access, j, scaled(a.factor(access, j))),
+            ]
+            .concat(),
+        }
+    }
+}
+
+impl LayerInput {
+    /// Every node used by the layer, including the previous layer for `LayerInput::Previous`.
+    pub fn nodes(&self, n: usize) -> Vec<Node> {
+        match self {
+            LayerInput::Previous if n == 0 => vec![Node::Input],
+            LayerInput::Previous => vec![Node::Layer(n - 1)],
+            LayerInput::Concat(merges) => merges.iter().flat_map(|m| m.nodes()).collect(),
+        }
+    }
+}
+
+/// Order the layers so every layer comes after the layers it takes input from.
+/// Layers are kept in the order they are defined in when possible.
+/// Returns the index of a layer in a cycle, if there is one.
+pub fn topological_order(inputs: &[LayerInput]) -> Result<Vec<usize>, usize> {
+    let dependencies: Vec<Vec<usize>> = inputs
+        .iter()
+        .enumerate()
+        .map(|(n, input)| {
+            input
+                .nodes(n)
+                .into_iter()
+                .filter_map(|node| match node {
+                    Node::Layer(k) => Some(k),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut order = Vec::with_capacity(inputs.len());
+    let mut done = vec![false; inputs.len()];
+    while order.len() < inputs.len() {
+        match (0..inputs.len()).find(|&n| !done[n] && dependencies[n].iter().all(|&k| done[k])) {
+            Some(next) => {
+                done[next] = true;
+                order.push(next);
+            }
+            None => {
+                // Every remaining layer depends on another remaining layer,
+                // so following the dependencies long enough ends up in a cycle.
+                let mut n = (0..inputs.len()).find(|&n| !done[n]).unwrap();
+                for _ in 0..inputs.len() {
+                    n = *dependencies[n].iter().find(|&&k| !done[k]).unwrap();
+                }
+                return Err(n);
+            }
+        }
+    }
+    Ok(order)
+}
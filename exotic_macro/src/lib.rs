@@ -1,246 +1,2035 @@
 extern crate proc_macro;
-use std::fmt;
 
+mod graph;
+
+use graph::*;
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use quote::*;
-use ron::from_str;
-use serde::de::{self, Deserialize, Visitor};
+use syn::{
+    braced, parenthesized,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    token, Attribute, Expr, GenericArgument, Ident, PathArguments, Token, Type, Visibility,
+};
 
-macro_rules! bail{
-    ($($t: tt)*) => {{
-        let err = format!($($t)*);
-        return quote!{compile_error!(#err);}.into()
-    }}
+/// A single layer of a model, written as a struct field with an optional initializer.
+/// The field name can be followed by a parenthesized list of the inputs of the layer.
+struct ModelLayer {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    sources: Option<(token::Paren, Punctuated<Expr, Token![,]>)>,
+    /// The type of a layer that shares its parameters is that of the layer it shares them with,
+    /// which isn't known until the whole model is parsed.
+    ty: Type,
+    init: Option<Expr>,
+    shares: Option<SharedLayer>,
+    /// Whether the layer is marked `#[frozen]`, so it starts out frozen.
+    frozen: bool,
 }
 
-struct Ident(String);
-struct IdentVisitor;
+/// The layer whose parameters a layer uses, written as `name = other` or `name = other.transposed()` instead of a type.
+struct SharedLayer {
+    layer: Ident,
+    transposed: bool,
+}
 
-impl<'de> Visitor<'de> for IdentVisitor {
-    type Value = Ident;
+impl Parse for SharedLayer {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let expr: Expr = input.parse()?;
+        match &expr {
+            Expr::Path(path) if path.path.get_ident().is_some() => Ok(Self {
+                layer: path.path.get_ident().unwrap().clone(),
+                transposed: false,
+            }),
+            Expr::MethodCall(call) if call.method == "transposed" && call.args.is_empty() => {
+                match &*call.receiver {
+                    Expr::Path(path) if path.path.get_ident().is_some() => Ok(Self {
+                        layer: path.path.get_ident().unwrap().clone(),
+                        transposed: true,
+                    }),
+                    _ => Err(syn::Error::new_spanned(
+                        &call.receiver,
+                        "expected the name of a layer",
+                    )),
+                }
+            }
+            _ => Err(syn::Error::new_spanned(
+                expr,
+                "expected the name of a layer, or `layer.transposed()`",
+            )),
+        }
+    }
+}
 
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("Any identifier")
+/// Remove the attribute `#[name]` from `attrs`, and return whether it was there.
+fn take_flag(attrs: &mut Vec<Attribute>, name: &str) -> syn::Result<bool> {
+    let mut found = false;
+    for attr in attrs.iter() {
+        if attr.path.is_ident(name) {
+            if found || !attr.tokens.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    format!("expected a single `#[{name}]`"),
+                ));
+            }
+            found = true;
+        }
     }
+    attrs.retain(|attr| !attr.path.is_ident(name));
+    Ok(found)
+}
 
-    fn visit_str<E>(self, value: &str) -> Result<Ident, E>
-    where
-        E: de::Error,
-    {
-        Ok(Ident(value.to_string()))
+impl Parse for ModelLayer {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let frozen = take_flag(&mut attrs, "frozen")?;
+        let vis = input.parse()?;
+        let name = input.parse()?;
+        let sources = if input.peek(token::Paren) {
+            let content;
+            let paren = parenthesized!(content in input);
+            Some((paren, Punctuated::parse_terminated(&content)?))
+        } else {
+            None
+        };
+        if input.parse::<Option<Token![=]>>()?.is_some() {
+            return Ok(Self {
+                attrs,
+                vis,
+                name,
+                sources,
+                ty: Type::Infer(syn::TypeInfer {
+                    underscore_token: Default::default(),
+                }),
+                init: None,
+                shares: Some(input.parse()?),
+                frozen,
+            });
+        }
+        input.parse::<Token![:]>()?;
+        let ty = input.parse()?;
+        let init = if input.parse::<Option<Token![=]>>()?.is_some() {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            attrs,
+            vis,
+            name,
+            sources,
+            ty,
+            init,
+            shares: None,
+            frozen,
+        })
     }
 }
 
-impl<'de> Deserialize<'de> for Ident {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let ret = deserializer.deserialize_identifier(IdentVisitor)?;
-        Ok(ret)
+/// A named input of a model, written as `name: length`.
+struct NamedInput {
+    name: Ident,
+    len: Expr,
+}
+
+impl Parse for NamedInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let len = input.parse()?;
+        Ok(Self { name, len })
     }
 }
 
 struct Model {
-    derive: Vec<proc_macro2::Ident>,
-    name: proc_macro2::Ident,
-    float_type: proc_macro2::TokenStream,
-    input_len: usize,
-    output_len: usize,
-    cache_len: proc_macro2::TokenStream,
-    layers: (Vec<proc_macro2::TokenStream>, Vec<proc_macro2::TokenStream>),
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    name: Ident,
+    float_type: TokenStream2,
+    input_len: TokenStream2,
+    output_len: TokenStream2,
+    cache_len: TokenStream2,
+    layers: Vec<ModelLayer>,
+    /// The layer holding the parameters of every layer, which is the layer itself unless it shares them.
+    owners: Vec<usize>,
+    /// The named inputs of the model, which are concatenated into its input.
+    named_inputs: Vec<NamedInput>,
+    /// Where the input of every layer comes from.
+    inputs: Vec<LayerInput>,
+    /// The layers whose outputs are concatenated into the output of the model.
+    outputs: Vec<usize>,
+    /// The order the layers are run in, so every layer runs after the layers it takes input from.
+    order: Vec<usize>,
+    /// Whether the signature was written out, instead of being inferred from the layers.
+    explicit_signature: bool,
+    /// Whether `BatchLayer`, `Parameters` and `Serialization` are implemented,
+    /// from the `#[batch]`, `#[parameters]` and `#[serialize]` attributes of the model.
+    batch: bool,
+    parameters: bool,
+    serialize: bool,
 }
 
-#[derive(serde::Deserialize)]
-struct ModelRon {
-    derive: Vec<Ident>,
-    name: String,
-    float_type: String,
-    input_len: usize,
-    output_len: usize,
-    layers: Vec<(String, String)>,
-}
+impl Parse for Model {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let batch = take_flag(&mut attrs, "batch")?;
+        let parameters = take_flag(&mut attrs, "parameters")?;
+        let serialize = take_flag(&mut attrs, "serialize")?;
+        let vis = input.parse()?;
+        input.parse::<Token![struct]>()?;
+        let name: Ident = input.parse()?;
+
+        let named_inputs: Vec<NamedInput> = if input.peek(token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            Punctuated::<NamedInput, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let output_names: Option<Vec<Ident>> = if input.parse::<Option<Token![->]>>()?.is_some() {
+            if input.peek(token::Paren) {
+                let content;
+                let paren = parenthesized!(content in input);
+                let names: Vec<_> = Punctuated::<Ident, Token![,]>::parse_terminated(&content)?
+                    .into_iter()
+                    .collect();
+                if names.is_empty() {
+                    return Err(syn::Error::new(
+                        paren.span,
+                        "a model needs at least one output",
+                    ));
+                }
+                Some(names)
+            } else {
+                Some(vec![input.parse()?])
+            }
+        } else {
+            None
+        };
+
+        // The signature is optional, as it can be inferred from the first and last layer.
+        let signature = if input.parse::<Option<Token![:]>>()?.is_some() {
+            let signature: syn::Path = input.parse()?;
+            let expected = "expected `Layer<float type, input length, output length>`";
+            let args = match signature.segments.last() {
+                Some(segment) if segment.ident == "Layer" && signature.segments.len() == 1 => {
+                    match &segment.arguments {
+                        PathArguments::AngleBracketed(args) if args.args.len() == 3 => &args.args,
+                        _ => return Err(syn::Error::new_spanned(&signature, expected)),
+                    }
+                }
+                _ => return Err(syn::Error::new_spanned(&signature, expected)),
+            };
+            let float_type = match &args[0] {
+                GenericArgument::Type(t) => t.to_token_stream(),
+                arg => return Err(syn::Error::new_spanned(arg, "expected a float type")),
+            };
+            let len = |arg: &GenericArgument| match arg {
+                GenericArgument::Type(_) | GenericArgument::Const(_) => Ok(arg.to_token_stream()),
+                arg => Err(syn::Error::new_spanned(arg, "expected a length")),
+            };
+            Some((float_type, len(&args[1])?, len(&args[2])?))
+        } else {
+            None
+        };
+
+        let content;
+        let braces = braced!(content in input);
+        let mut layers: Vec<ModelLayer> =
+            Punctuated::<ModelLayer, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .collect();
 
-impl ModelRon {
-    fn to_model(self) -> Model {
-        Model {
-            derive: self
-                .derive
+        if layers.is_empty() {
+            return Err(syn::Error::new(
+                braces.span,
+                "a model needs at least one layer",
+            ));
+        }
+        for (n, layer) in layers.iter().enumerate() {
+            if layers[..n].iter().any(|l| l.name == layer.name) {
+                return Err(syn::Error::new_spanned(
+                    &layer.name,
+                    format!("layer `{}` is defined more than once", layer.name),
+                ));
+            }
+            if named_inputs.iter().any(|i| i.name == layer.name) {
+                return Err(syn::Error::new_spanned(
+                    &layer.name,
+                    format!(
+                        "layer `{}` has the same name as an input of `{name}`",
+                        layer.name
+                    ),
+                ));
+            }
+        }
+        for (n, named) in named_inputs.iter().enumerate() {
+            if named.name == "input" {
+                return Err(syn::Error::new_spanned(
+                    &named.name,
+                    "`input` is reserved for the whole input of the model",
+                ));
+            }
+            if named_inputs[..n].iter().any(|i| i.name == named.name) {
+                return Err(syn::Error::new_spanned(
+                    &named.name,
+                    format!("input `{}` is defined more than once", named.name),
+                ));
+            }
+        }
+
+        let mut owners = vec![];
+        for (n, layer) in layers.iter().enumerate() {
+            let shared = match &layer.shares {
+                Some(shared) => shared,
+                None => {
+                    owners.push(n);
+                    continue;
+                }
+            };
+            let owner = layers
                 .iter()
-                .map(|t| format_ident!("{}", t.0))
-                .collect(),
-            name: format_ident!("{}", self.name),
-            float_type: self.float_type.parse().unwrap(),
-            input_len: self.input_len,
-            output_len: self.output_len,
-            layers: (
-                self.layers
-                    .iter()
-                    .map(|(t, _)| t.parse().unwrap())
-                    .collect(),
-                self.layers
-                    .iter()
-                    .map(|(_, f)| f.parse().unwrap())
-                    .collect(),
-            ),
-            cache_len: {
-                let lt = self
-                    .layers
-                    .iter()
-                    .map(|(t, _)| t.parse::<TokenStream2>().unwrap());
-                quote! {{#(#lt::O_LEN +)* 0}}
+                .position(|l| l.name == shared.layer)
+                .ok_or_else(|| {
+                    syn::Error::new_spanned(
+                        &shared.layer,
+                        format!("there is no layer named `{}` in `{name}`", shared.layer),
+                    )
+                })?;
+            if layers[owner].shares.is_some() {
+                return Err(syn::Error::new_spanned(
+                    &shared.layer,
+                    format!(
+                        "`{}` uses the parameters of another layer, use those directly",
+                        shared.layer
+                    ),
+                ));
+            }
+            if !layer.attrs.is_empty()
+                || layer.frozen
+                || !matches!(layer.vis, Visibility::Inherited)
+            {
+                return Err(syn::Error::new_spanned(
+                    &layer.name,
+                    format!(
+                        "`{}` uses the parameters of `{}`, so it isn't a field of `{name}`, \
+                        and can't have attributes or a visibility",
+                        layer.name, shared.layer
+                    ),
+                ));
+            }
+            owners.push(owner);
+        }
+        for (n, &owner) in owners.iter().enumerate() {
+            if let Some(shared) = &layers[n].shares {
+                let ty = &layers[owner].ty;
+                layers[n].ty = if shared.transposed {
+                    syn::parse_quote! {exotic::transpose::Transposed<'static, #ty>}
+                } else {
+                    ty.clone()
+                };
+            }
+        }
+
+        let inputs = layers
+            .iter()
+            .map(|layer| {
+                if layer.name == "input" {
+                    return Err(syn::Error::new_spanned(
+                        &layer.name,
+                        "`input` is reserved for the input of the model",
+                    ));
+                }
+                let (paren, sources) = match &layer.sources {
+                    Some(sources) => sources,
+                    None => return Ok(LayerInput::Previous),
+                };
+                if sources.is_empty() {
+                    return Err(syn::Error::new(
+                        paren.span,
+                        "expected the inputs of the layer",
+                    ));
+                }
+
+                let resolve = |ident: &Ident| {
+                    if ident == "input" {
+                        return Ok(Node::Input);
+                    }
+                    if let Some(k) = named_inputs.iter().position(|i| &i.name == ident) {
+                        return Ok(Node::NamedInput(k));
+                    }
+                    match layers.iter().position(|l| &l.name == ident) {
+                        Some(k) => Ok(Node::Layer(k)),
+                        None => Err(syn::Error::new_spanned(
+                            ident,
+                            format!("there is no layer or input named `{ident}` in `{name}`"),
+                        )),
+                    }
+                };
+                Ok(LayerInput::Concat(
+                    sources
+                        .iter()
+                        .map(|source| Merge::parse(source, &resolve))
+                        .collect::<syn::Result<_>>()?,
+                ))
+            })
+            .collect::<syn::Result<Vec<_>>>()?;
+
+        let order = topological_order(&inputs).map_err(|k| {
+            syn::Error::new_spanned(
+                &layers[k].name,
+                format!(
+                    "layer `{}` in `{name}` takes input from its own output",
+                    layers[k].name
+                ),
+            )
+        })?;
+
+        let outputs = match output_names {
+            None => vec![layers.len() - 1],
+            Some(names) => {
+                let mut outputs = vec![];
+                for output in &names {
+                    let k = layers
+                        .iter()
+                        .position(|l| &l.name == output)
+                        .ok_or_else(|| {
+                            syn::Error::new_spanned(
+                                output,
+                                format!("there is no layer named `{output}` in `{name}`"),
+                            )
+                        })?;
+                    if outputs.contains(&k) {
+                        return Err(syn::Error::new_spanned(
+                            output,
+                            format!("`{output}` is listed as an output more than once"),
+                        ));
+                    }
+                    outputs.push(k);
+                }
+                outputs
+            }
+        };
+
+        if signature.is_none()
+            && named_inputs.is_empty()
+            && matches!(inputs[0], LayerInput::Concat(_))
+        {
+            return Err(syn::Error::new_spanned(
+                &layers[0].name,
+                "can't infer the input length of the model when its first layer has a list of inputs, \
+                try adding `: Layer<float type, input length, output length>` after the model name",
+            ));
+        }
+
+        let explicit_signature = signature.is_some();
+        let (float_type, input_len, output_len) = match signature {
+            Some(signature) => signature,
+            None => {
+                let first = &layers[0].ty;
+                let float_type = infer_float_type(&layers[owners[0]].ty).ok_or_else(|| {
+                    syn::Error::new_spanned(
+                        first,
+                        "can't infer the float type of the model from this layer, \
+                        try adding `: Layer<float type, input length, output length>` after the model name",
+                    )
+                })?;
+                let input_len = if named_inputs.is_empty() {
+                    quote! {{<#first>::I_LEN}}
+                } else {
+                    let lens = named_inputs.iter().map(|i| &i.len);
+                    quote! {{#(#lens)+*}}
+                };
+                let output_types = outputs.iter().map(|&k| &layers[k].ty);
+                let output_len = quote! {{#(<#output_types>::O_LEN)+*}};
+                (float_type, input_len, output_len)
+            }
+        };
+
+        // Every layer gets its whole buffer in the cache, which is longer than its output for nested models.
+        // With several outputs, their concatenation is stored after the buffers of the layers.
+        let lt = layers.iter().map(|l| &l.ty);
+        let cache_len = if outputs.len() == 1 {
+            quote! {{#(<#lt>::BUFFER_LEN)+*}}
+        } else {
+            quote! {{#(<#lt>::BUFFER_LEN)+* + #output_len}}
+        };
+
+        Ok(Self {
+            attrs,
+            vis,
+            name,
+            float_type,
+            input_len,
+            output_len,
+            cache_len,
+            layers,
+            owners,
+            named_inputs,
+            inputs,
+            outputs,
+            order,
+            explicit_signature,
+            batch,
+            parameters,
+            serialize,
+        })
+    }
+}
+
+/// Where the gradient of the output of a layer comes from in `backpropagate`.
+#[derive(PartialEq, Eq)]
+enum GradientSource {
+    /// The gradient passed to the model, when the layer is its only output, and isn't used by other layers.
+    Given,
+    /// The gradient of the next layer, when it is the only layer using the output.
+    Next,
+    /// The sum of the gradients of every use of the output.
+    Summed,
+}
+
+impl Model {
+    /// The output length of a node.
+    fn node_len(&self, node: Node) -> TokenStream2 {
+        match node {
+            Node::Input => self.input_len.clone(),
+            Node::NamedInput(k) => self.named_inputs[k].len.to_token_stream(),
+            Node::Layer(k) => {
+                let ty = &self.layers[k].ty;
+                quote! {<#ty>::O_LEN}
+            }
+        }
+    }
+
+    /// The length of the buffer of a layer.
+    fn buffer_len(&self, k: usize) -> TokenStream2 {
+        let ty = &self.layers[k].ty;
+        quote! {<#ty>::BUFFER_LEN}
+    }
+
+    /// The output of layer `k`, from the end of its buffer.
+    fn layer_output(&self, k: usize, buffer: TokenStream2) -> TokenStream2 {
+        let (float_type, ty) = (&self.float_type, &self.layers[k].ty);
+        quote! {
+            <#ty as Layer<#float_type, {<#ty>::I_LEN}, {<#ty>::O_LEN}, {<#ty>::BUFFER_LEN}>>::output(#buffer)
+        }
+    }
+
+    /// Expression for the layer `k` is run with, which is the field holding its parameters,
+    /// or a transposed view of it.
+    fn layer(&self, k: usize) -> TokenStream2 {
+        let owner = &self.layers[self.owners[k]].name;
+        match &self.layers[k].shares {
+            Some(shared) if shared.transposed => {
+                quote! {exotic::transpose::Transposed(&mut self.#owner)}
+            }
+            _ => quote! {self.#owner},
+        }
+    }
+
+    /// The fields of the model, which are the layers that don't share the parameters of another layer.
+    fn fields(&self) -> Vec<&Ident> {
+        (0..self.layers.len())
+            .filter(|&k| self.owners[k] == k)
+            .map(|k| &self.layers[k].name)
+            .collect()
+    }
+
+    /// The layers whose parameters are used by other layers as well.
+    /// They accumulate the gradients of every use during `backpropagate`, and apply them together at the end.
+    /// Returns the setup of the accumulation, and the update.
+    fn shared_updates(&self) -> (TokenStream2, TokenStream2) {
+        let shared: Vec<_> = (0..self.layers.len())
+            .filter(|&k| self.owners.iter().filter(|&&o| o == k).count() > 1)
+            .collect();
+        let fields: Vec<_> = shared.iter().map(|&k| &self.layers[k].name).collect();
+        let accumulating: Vec<_> = shared.iter().map(|k| format_ident!("a{k}")).collect();
+        (
+            quote! {
+                #(
+                    let #accumulating = self.#fields.accumulating();
+                    self.#fields.set_accumulate(true);
+                )*
+            },
+            quote! {
+                #(
+                    if !#accumulating {
+                        self.#fields.set_accumulate(false);
+                        self.#fields.apply_gradients();
+                    }
+                )*
             },
+        )
+    }
+
+    fn node_name(&self, node: Node) -> String {
+        match node {
+            Node::Input => "input".to_string(),
+            Node::NamedInput(k) => self.named_inputs[k].name.to_string(),
+            Node::Layer(k) => self.layers[k].name.to_string(),
+        }
+    }
+
+    /// The range of a named input in the input of the model.
+    fn input_range(&self, k: usize) -> TokenStream2 {
+        let lens: Vec<_> = self
+            .named_inputs
+            .iter()
+            .map(|i| i.len.to_token_stream())
+            .collect();
+        ranges(&lens).swap_remove(k)
+    }
+
+    /// The range of every output in the output of the model.
+    fn output_ranges(&self) -> Vec<TokenStream2> {
+        let lens: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|&k| self.node_len(Node::Layer(k)))
+            .collect();
+        ranges(&lens)
+    }
+
+    /// The layers in the order their buffers are stored in the cache.
+    /// The outputs of the model are stored last, so a single output forms the output of the cache.
+    fn layout(&self) -> Vec<usize> {
+        (0..self.layers.len())
+            .filter(|k| !self.outputs.contains(k))
+            .chain(self.outputs.iter().copied())
+            .collect()
+    }
+
+    /// Number of times the output of a node is used as (part of) the input of a layer.
+    fn uses(&self, node: Node) -> usize {
+        self.inputs
+            .iter()
+            .enumerate()
+            .flat_map(|(n, input)| input.nodes(n))
+            .filter(|n| *n == node)
+            .count()
+    }
+
+    /// Whether the output of a node is part of the merged input of a layer.
+    fn is_merged(&self, node: Node) -> bool {
+        self.inputs.iter().any(|input| match input {
+            LayerInput::Concat(merges) => merges.iter().any(|m| m.nodes().contains(&node)),
+            LayerInput::Previous => false,
+        })
+    }
+
+    /// Whether the output of a node is only used as the input of the next layer,
+    /// in which case its gradient can be passed straight on, instead of being summed.
+    fn is_direct(&self, node: Node) -> bool {
+        let next = match node {
+            Node::Input if self.named_inputs.is_empty() => 0,
+            Node::Layer(k) if !self.outputs.contains(&k) => k + 1,
+            _ => return false,
+        };
+        next < self.layers.len()
+            && matches!(self.inputs[next], LayerInput::Previous)
+            && self.uses(node) == 1
+    }
+
+    fn gradient_source(&self, k: usize) -> GradientSource {
+        if self.outputs == [k] && self.uses(Node::Layer(k)) == 0 {
+            GradientSource::Given
+        } else if self.is_direct(Node::Layer(k)) {
+            GradientSource::Next
+        } else {
+            GradientSource::Summed
         }
     }
+
+    /// Whether the model input is needed by more than the first layer,
+    /// in which case it is shadowed with a reference to it.
+    fn shares_input(&self) -> bool {
+        self.is_merged(Node::Input) || !self.named_inputs.is_empty()
+    }
+
+    /// Expression that can be indexed to get the output of a node.
+    /// `i` is the input of the model, and `layer` gives the output of a layer.
+    fn access(
+        &self,
+        node: Node,
+        i: TokenStream2,
+        layer: impl Fn(usize) -> TokenStream2,
+    ) -> TokenStream2 {
+        match node {
+            Node::Input => i,
+            Node::NamedInput(k) => {
+                let range = self.input_range(k);
+                quote! {#i[#range]}
+            }
+            Node::Layer(k) => layer(k),
+        }
+    }
+
+    /// The length of every concatenated merge, and its offset in the concatenation.
+    fn offsets<'a>(
+        &self,
+        merges: &'a [Merge],
+    ) -> Vec<(&'a Merge, TokenStream2, Option<TokenStream2>)> {
+        let lens: Vec<_> = merges
+            .iter()
+            .map(|merge| self.node_len(merge.nodes()[0]))
+            .collect();
+        merges
+            .iter()
+            .zip(offsets(&lens))
+            .zip(lens)
+            .map(|((merge, offset), len)| (merge, len, offset))
+            .collect()
+    }
+
+    /// Statements writing the merged input of a layer into `target`.
+    /// `access` gives an indexable expression for the output of a node.
+    fn fill_input(
+        &self,
+        merges: &[Merge],
+        target: TokenStream2,
+        access: &impl Fn(Node) -> TokenStream2,
+    ) -> TokenStream2 {
+        let fill = self
+            .offsets(merges)
+            .into_iter()
+            .map(|(merge, len, offset)| {
+                let range = range(&offset, &len);
+                match merge {
+                    Merge::Node(node) => {
+                        let node = access(*node);
+                        quote! {
+                            #target[#range].copy_from_slice(&#node[..]);
+                        }
+                    }
+                    _ => {
+                        let element = merge.element(access, &quote! {j});
+                        quote! {
+                            for (j, x) in #target[#range].iter_mut().enumerate() {
+                                *x = #element;
+                            }
+                        }
+                    }
+                }
+            });
+        quote! {#(#fill)*}
+    }
+
+    /// Statements adding the gradient `g` of a merged input to the gradients of its nodes.
+    /// `gradient` gives an indexable expression for the summed gradient of a node.
+    fn route_gradient(
+        &self,
+        merges: &[Merge],
+        access: &impl Fn(Node) -> TokenStream2,
+        gradient: &impl Fn(Node) -> TokenStream2,
+    ) -> TokenStream2 {
+        let route = self
+            .offsets(merges)
+            .into_iter()
+            .flat_map(|(merge, len, offset)| {
+                let index = match offset {
+                    Some(offset) => quote! {#offset + j},
+                    None => quote! {j},
+                };
+                merge
+                    .partials(access, &quote! {j}, None)
+                    .into_iter()
+                    .map(move |(node, partial)| {
+                        let d = gradient(node);
+                        let g = match partial {
+                            Some(partial) => quote! {g[#index] * #partial},
+                            None => quote! {g[#index]},
+                        };
+                        quote! {
+                            for j in 0..#len {
+                                #d[j] += #g;
+                            }
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            });
+        quote! {#(#route)*}
+    }
+}
+
+/// The offset of every vector in a concatenation of vectors with the given lengths.
+/// An offset of `None` means zero.
+fn offsets(lens: &[TokenStream2]) -> Vec<Option<TokenStream2>> {
+    let mut offset: Option<TokenStream2> = None;
+    lens.iter()
+        .map(|len| {
+            let this = offset.clone();
+            offset = Some(match offset.take() {
+                Some(offset) => quote! {#offset + #len},
+                None => len.clone(),
+            });
+            this
+        })
+        .collect()
+}
+
+fn range(offset: &Option<TokenStream2>, len: &TokenStream2) -> TokenStream2 {
+    match offset {
+        Some(offset) => quote! {#offset..#offset + #len},
+        None => quote! {..#len},
+    }
+}
+
+/// The range of every vector in a concatenation of vectors with the given lengths.
+fn ranges(lens: &[TokenStream2]) -> Vec<TokenStream2> {
+    offsets(lens)
+        .iter()
+        .zip(lens)
+        .map(|(offset, len)| range(offset, len))
+        .collect()
+}
+
+/// Name of the local variable holding the summed gradient of a node.
+fn gradient_name(node: Node) -> proc_macro2::Ident {
+    match node {
+        Node::Input | Node::NamedInput(_) => format_ident!("di"),
+        Node::Layer(k) => format_ident!("d{k}"),
+    }
+}
+
+/// The float type of a layer, assuming it is the first generic argument (like in `DenseLayer<f32, ...>`).
+fn infer_float_type(ty: &Type) -> Option<TokenStream2> {
+    let segment = match ty {
+        Type::Path(ty) => ty.path.segments.last()?,
+        _ => return None,
+    };
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(t) => Some(t.to_token_stream()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Compile-time checks that the inputs of every layer fit its input length,
+/// and that the model signature agrees with its inputs and outputs.
+fn assertions(model: &Model) -> TokenStream2 {
+    let Model {
+        name,
+        input_len,
+        output_len,
+        layers,
+        named_inputs,
+        inputs,
+        outputs,
+        explicit_signature,
+        ..
+    } = model;
+
+    let named = if *explicit_signature && !named_inputs.is_empty() {
+        let lens = named_inputs.iter().map(|i| &i.len);
+        let msg = format!("the named inputs of `{name}` don't add up to its input length");
+        quote_spanned! {name.span()=> assert!(#(#lens)+* == #input_len, #msg); }
+    } else {
+        quote! {}
+    };
+
+    let checks = layers.iter().zip(inputs).enumerate().map(|(n, (layer, input))| {
+        let ty = &layer.ty;
+        match input {
+            LayerInput::Previous if n == 0 => {
+                if !explicit_signature && named_inputs.is_empty() {
+                    return quote! {};
+                }
+                let msg = format!(
+                    "the input length of `{name}` doesn't match the input length of its first layer `{}`",
+                    layer.name
+                );
+                quote_spanned! {layer.name.span()=> assert!(<#ty>::I_LEN == #input_len, #msg); }
+            }
+            LayerInput::Previous => {
+                let prev = &layers[n - 1];
+                let prev_ty = &prev.ty;
+                let msg = format!(
+                    "the output length of layer `{}` in `{name}` doesn't match the input length of layer `{}`",
+                    prev.name, layer.name
+                );
+                quote_spanned! {layer.name.span()=> assert!(<#prev_ty>::O_LEN == <#ty>::I_LEN, #msg); }
+            }
+            LayerInput::Concat(merges) => {
+                let combined = merges.iter().flat_map(|merge| {
+                    let nodes = merge.nodes();
+                    let first_len = model.node_len(nodes[0]);
+                    let first_name = model.node_name(nodes[0]);
+                    nodes[1..]
+                        .iter()
+                        .map(|&node| {
+                            let len = model.node_len(node);
+                            let msg = format!(
+                                "`{first_name}` and `{}` are combined in the input of layer `{}` in `{name}`, but don't have the same length",
+                                model.node_name(node),
+                                layer.name
+                            );
+                            quote_spanned! {layer.name.span()=> assert!(#first_len == #len, #msg); }
+                        })
+                        .collect::<Vec<_>>()
+                });
+                let lens = merges.iter().map(|merge| model.node_len(merge.nodes()[0]));
+                let msg = format!(
+                    "the inputs of layer `{}` in `{name}` don't add up to its input length",
+                    layer.name
+                );
+                quote_spanned! {layer.name.span()=>
+                    #(#combined)*
+                    assert!(#(#lens)+* == <#ty>::I_LEN, #msg);
+                }
+            }
+        }
+    });
+
+    let output = if !explicit_signature {
+        quote! {}
+    } else if let [last] = outputs[..] {
+        let last = &layers[last];
+        let last_ty = &last.ty;
+        let msg = format!(
+            "the output length of `{name}` doesn't match the output length of its last layer `{}`",
+            last.name
+        );
+        quote_spanned! {last.name.span()=> assert!(<#last_ty>::O_LEN == #output_len, #msg); }
+    } else {
+        let lens = outputs.iter().map(|&k| model.node_len(Node::Layer(k)));
+        let msg = format!("the outputs of `{name}` don't add up to its output length");
+        quote_spanned! {name.span()=> assert!(#(#lens)+* == #output_len, #msg); }
+    };
+
+    quote! {
+        #[allow(clippy::assertions_on_constants)]
+        const _: () = {
+            #named
+            #(#checks)*
+            #output
+        };
+    }
 }
 
+/// Names of the local variables holding the gradient of every layer.
 fn layer_names(len: usize) -> Vec<proc_macro2::Ident> {
     (0..len).map(|n| format_ident!("l{n}")).collect()
 }
 
+/// Names of the local variables holding the cached output of every layer.
+fn output_names(len: usize) -> Vec<proc_macro2::Ident> {
+    (0..len).map(|n| format_ident!("o{n}")).collect()
+}
+
+/// Split the cache into the buffer of every layer, named `b{k}`.
+/// `mutable` splits it into mutable buffers, for `predict`, along with the concatenated output of the model when it has several outputs.
+/// Otherwise the output of every layer that is used by other layers is also taken from its buffer, as `o{k}`.
+fn split_cache(model: &Model, mutable: bool) -> TokenStream2 {
+    let Model {
+        float_type,
+        output_len,
+        layers,
+        outputs: model_outputs,
+        ..
+    } = model;
+
+    let (split, reference) = if mutable {
+        (quote! {split_at_mut}, quote! {&mut})
+    } else {
+        (quote! {split_at}, quote! {&})
+    };
+    let concat_output = mutable && model_outputs.len() > 1;
+
+    let outputs = output_names(layers.len());
+    let layout = model.layout();
+    let splits = layout.iter().enumerate().map(|(n, &k)| {
+        let (b, len) = (format_ident!("b{k}"), model.buffer_len(k));
+        let rest = if n == layout.len() - 1 && !concat_output {
+            quote! {_}
+        } else {
+            quote! {buffer}
+        };
+        let output = if mutable || model.uses(Node::Layer(k)) == 0 {
+            quote! {}
+        } else {
+            let (o, output) = (&outputs[k], model.layer_output(k, b.to_token_stream()));
+            quote! {
+                let #o = #output;
+            }
+        };
+        quote! {
+            let (#b, #rest) = buffer.#split(#len);
+            let #b: #reference [#float_type; {#len}] = #b.try_into()?;
+            #output
+        }
+    });
+    let model_output = if concat_output {
+        quote! {
+            let model_output: &mut [#float_type; #output_len] = buffer.try_into()?;
+        }
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        #(#splits)*
+        #model_output
+    }
+}
+
+/// The merged input of layer `n`, as a block expression.
+fn merged_input(
+    model: &Model,
+    n: usize,
+    merges: &[Merge],
+    access: &impl Fn(Node) -> TokenStream2,
+) -> TokenStream2 {
+    let (float_type, ty) = (&model.float_type, &model.layers[n].ty);
+    let fill = model.fill_input(merges, quote! {x}, access);
+    quote! {{
+        let mut x: [#float_type; {<#ty>::I_LEN}] = [0.; {<#ty>::I_LEN}];
+        #fill
+        x
+    }}
+}
+
+/// Shadow the input of the model with a reference to it, when it is used by more than the first layer.
+fn shared_input(model: &Model) -> TokenStream2 {
+    if model.shares_input() {
+        quote! {let i = i.moo_ref();}
+    } else {
+        quote! {}
+    }
+}
+
 fn predict(model: &Model) -> TokenStream2 {
     let Model {
         float_type,
         input_len,
         layers,
+        inputs,
+        outputs: model_outputs,
+        order,
         cache_len,
         ..
     } = model;
 
-    let layer_names = layer_names(layers.0.len());
+    let outputs = output_names(layers.len());
+    let split = split_cache(model, true);
+    let shared_input = shared_input(model);
+    let access = |node| model.access(node, quote! {i}, |k| outputs[k].to_token_stream());
+    let concat_output = model_outputs.len() > 1;
 
-    let layer_inputs: Vec<_> = (0..layers.0.len())
-        .map(|n| {
-            if n == 0 {
-                format_ident!("i")
+    let steps = order.iter().map(|&n| {
+        let (layer, b) = (model.layer(n), format_ident!("b{n}"));
+        let predict = match &inputs[n] {
+            LayerInput::Previous if n == 0 => quote! {
+                #layer.predict(i, #b)?;
+            },
+            LayerInput::Previous => {
+                let prev = &outputs[n - 1];
+                quote! {
+                    #layer.predict(#prev, #b)?;
+                }
+            }
+            LayerInput::Concat(merges) => {
+                let x = merged_input(model, n, merges, &access);
+                quote! {
+                    let x = #x;
+                    #layer.predict(&x, #b)?;
+                }
+            }
+        };
+        // The output is only taken from the buffer once the layer is done with it.
+        let output =
+            if model.uses(Node::Layer(n)) > 0 || (concat_output && model_outputs.contains(&n)) {
+                let (o, output) = (&outputs[n], model.layer_output(n, quote! {&*#b}));
+                quote! {
+                    let #o = #output;
+                }
             } else {
-                let n = n - 1;
-                format_ident!("l{n}")
+                quote! {}
+            };
+        quote! {
+            #predict
+            #output
+        }
+    });
+    let concat_output = if concat_output {
+        let ranges = model.output_ranges();
+        let output_layers = model_outputs.iter().map(|&k| &outputs[k]);
+        quote! {
+            #(
+                model_output[#ranges].copy_from_slice(#output_layers);
+            )*
+        }
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        fn predict(&mut self, i: impl exotic::slas::prelude::StaticVec<#float_type, #input_len>, buffer: &mut impl StaticVec<#float_type, #cache_len>)
+        -> Result<()>{
+            #shared_input
+            let buffer = buffer.mut_moo_ref();
+            #split
+            #(#steps)*
+            #concat_output
+            Ok(())
+        }
+    }
+}
+
+fn backprop(model: &Model) -> TokenStream2 {
+    let Model {
+        float_type,
+        input_len,
+        output_len,
+        cache_len,
+        layers,
+        inputs,
+        outputs: model_outputs,
+        order,
+        ..
+    } = model;
+
+    let layer_names = layer_names(layers.len());
+    let outputs = output_names(layers.len());
+    let split = split_cache(model, false);
+    let shared_input = shared_input(model);
+    let access = |node| model.access(node, quote! {i}, |k| outputs[k].to_token_stream());
+    let gradient = |node| {
+        let d = gradient_name(node);
+        model.access(node, d.to_token_stream(), |_| d.to_token_stream())
+    };
+
+    // The gradients of nodes that are used more than once are summed, before being passed on.
+    // The gradients of the outputs of the model start out as their part of `gradient`.
+    let output_ranges = model.output_ranges();
+    let input_sum = if model.is_direct(Node::Input) {
+        quote! {}
+    } else {
+        quote! {
+            let mut di: [#float_type; #input_len] = [0.; #input_len];
+        }
+    };
+    let sums = order
+        .iter()
+        .filter(|&&k| model.gradient_source(k) == GradientSource::Summed)
+        .map(|&k| {
+            let (d, len) = (
+                gradient_name(Node::Layer(k)),
+                model.node_len(Node::Layer(k)),
+            );
+            match model_outputs.iter().position(|&o| o == k) {
+                // Outputs that aren't used by other layers only get their part of `gradient`.
+                Some(n) if model.uses(Node::Layer(k)) == 0 => {
+                    let range = &output_ranges[n];
+                    quote! {
+                        let #d: [#float_type; #len] = (&gradient[#range]).try_into()?;
+                    }
+                }
+                Some(n) => {
+                    let range = &output_ranges[n];
+                    quote! {
+                        let mut #d: [#float_type; #len] = (&gradient[#range]).try_into()?;
+                    }
+                }
+                None => quote! {
+                    let mut #d: [#float_type; #len] = [0.; #len];
+                },
+            }
+        });
+    let shared_gradient = if model_outputs
+        .iter()
+        .any(|&k| model.gradient_source(k) == GradientSource::Summed)
+    {
+        quote! {let gradient = gradient.moo_ref();}
+    } else {
+        quote! {}
+    };
+
+    let steps = order.iter().rev().map(|&n| {
+        let (layer, b, l) = (model.layer(n), format_ident!("b{n}"), &layer_names[n]);
+        let delta = match model.gradient_source(n) {
+            GradientSource::Given => quote! {gradient},
+            GradientSource::Next => layer_names[n + 1].to_token_stream(),
+            GradientSource::Summed => {
+                let d = gradient_name(Node::Layer(n));
+                quote! {&#d}
+            }
+        };
+
+        match &inputs[n] {
+            LayerInput::Previous => {
+                let (prev, i) = if n == 0 {
+                    (Node::Input, quote! {i})
+                } else {
+                    (Node::Layer(n - 1), outputs[n - 1].to_token_stream())
+                };
+                let route = if model.is_direct(prev) {
+                    quote! {}
+                } else {
+                    let (d, len) = (gradient_name(prev), model.node_len(prev));
+                    quote! {
+                        let g = #l.moo_ref();
+                        for j in 0..#len {
+                            #d[j] += g[j];
+                        }
+                    }
+                };
+                quote! {
+                    let #l = #layer.backpropagate(#i, #b, #delta)?;
+                    #route
+                }
+            }
+            LayerInput::Concat(merges) => {
+                let x = merged_input(model, n, merges, &access);
+                let route = model.route_gradient(merges, &access, &gradient);
+                quote! {
+                    let x = #x;
+                    let #l = #layer.backpropagate(&x, #b, #delta)?;
+                    let g = #l.moo_ref();
+                    #route
+                }
             }
+        }
+    });
+
+    let (accumulate_shared, update_shared) = model.shared_updates();
+
+    let ret = if model.is_direct(Node::Input) {
+        format_ident!("l0")
+    } else {
+        gradient_name(Node::Input)
+    };
+
+    quote! {
+        fn backpropagate(&mut self, i: impl exotic::slas::prelude::StaticVec<#float_type, #input_len>, buffer: &impl exotic::slas::prelude::StaticVec<#float_type, #cache_len>, gradient: impl exotic::slas::prelude::StaticVec<#float_type, #output_len>) -> Result<[#float_type; #input_len]>{
+            #shared_input
+            #shared_gradient
+            let buffer = buffer.moo_ref();
+            #split
+            #input_sum
+            #(#sums)*
+            #accumulate_shared
+            #(#steps)*
+            #update_shared
+
+            Ok(#ret)
+        }
+    }
+}
+
+/// Prediction without a cache.
+/// Layers whose output is passed straight to the next layer alternate between two buffers the size of the largest of their buffers,
+/// while every other layer gets its own buffer.
+fn infer(model: &Model) -> TokenStream2 {
+    let Model {
+        vis,
+        float_type,
+        input_len,
+        output_len,
+        layers,
+        inputs,
+        outputs,
+        order,
+        ..
+    } = model;
+
+    let position = |k| order.iter().position(|&n| n == k).unwrap();
+    let slotted: Vec<_> = (0..layers.len())
+        .map(|k| {
+            let next_only = model.is_direct(Node::Layer(k)) && position(k + 1) == position(k) + 1;
+            let last_output = outputs == &[k] && order.last() == Some(&k);
+            next_only || last_output
         })
         .collect();
 
-    //let ret = format_ident!("l{}", layers.0.len() - 1);
+    let shared_input = shared_input(model);
+    let saved = (0..layers.len()).filter(|&k| !slotted[k]).map(|k| {
+        let (s, len) = (format_ident!("s{k}"), model.buffer_len(k));
+        quote! {
+            let mut #s: [#float_type; {#len}] = [0.; {#len}];
+        }
+    });
+    let saved_output = |k: usize| {
+        let s = format_ident!("s{k}");
+        model.layer_output(k, quote! {&#s})
+    };
+    let access = |node| model.access(node, quote! {i}, saved_output);
 
-    let buffer_output: Vec<_> = (0..layers.0.len())
-        .map(|n| {
-            let ofset = {
-                let lt: Vec<_> = (0..n)
-                    .map(|n| {
-                        let t = &layers.0[n];
-                        quote! {#t::O_LEN}
-                    })
-                    .collect();
+    // The buffer holding the output of the last layer that didn't get its own.
+    let mut slot = 1;
+    let steps: Vec<_> = order
+        .iter()
+        .map(|&n| {
+            let layer = model.layer(n);
+            let from_slot = n > 0 && matches!(inputs[n], LayerInput::Previous) && slotted[n - 1];
+            let to_slot = slotted[n];
+
+            let buffers = match (from_slot, to_slot) {
+                (true, true) if slot == 0 => quote! { let [input, output] = &mut buffers; },
+                (true, true) => quote! { let [output, input] = &mut buffers; },
+                (true, false) if slot == 0 => quote! { let [input, _] = &buffers; },
+                (true, false) => quote! { let [_, input] = &buffers; },
+                (false, true) if slot == 0 => quote! { let [_, output] = &mut buffers; },
+                (false, true) => quote! { let [output, _] = &mut buffers; },
+                (false, false) => quote! {},
+            };
+
+            let (prepare, i) = match &inputs[n] {
+                LayerInput::Previous if n == 0 => (quote! {}, quote! {i}),
+                LayerInput::Previous if from_slot => {
+                    let len = model.buffer_len(n - 1);
+                    let output = model.layer_output(n - 1, quote! {input});
+                    (
+                        quote! {
+                            let input: &[#float_type; {#len}] = (&input[..#len]).try_into()?;
+                            let input = #output;
+                        },
+                        quote! {input},
+                    )
+                }
+                LayerInput::Previous => (quote! {}, saved_output(n - 1)),
+                LayerInput::Concat(merges) => {
+                    let x = merged_input(model, n, merges, &access);
+                    (quote! { let x = #x; }, quote! {&x})
+                }
+            };
 
-                quote! {{#(#lt +)* 0}}
+            let (output, o) = if to_slot {
+                slot = 1 - slot;
+                let len = model.buffer_len(n);
+                (
+                    quote! {
+                        let output: &mut [#float_type; {#len}] = (&mut output[..#len]).try_into()?;
+                    },
+                    quote! {output},
+                )
+            } else {
+                let s = format_ident!("s{n}");
+                (quote! {}, quote! {&mut #s})
             };
 
-            let layer = &layers.0[n];
-            quote! { unsafe{ std::mem::transmute::<_, MutStaticVecRef::<#float_type, {#layer::O_LEN}>>(o.as_mut_ptr().add(#ofset)) } }
+            quote! {{
+                #buffers
+                #prepare
+                #output
+                #layer.predict(#i, #o)?;
+            }}
         })
         .collect();
 
-    //let buffered_ret = format_ident!("l{}", layers.0.len() - 1);
+    let slot_lens: Vec<_> = (0..layers.len())
+        .filter(|&k| slotted[k])
+        .map(|k| model.buffer_len(k))
+        .collect();
+    let buffers = if slot_lens.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            const LEN: usize = exotic::max_len(&[#(#slot_lens),*]);
+            let mut buffers = [[0.; LEN]; 2];
+        }
+    };
+
+    let ret = match outputs[..] {
+        [k] if slotted[k] => {
+            let slot = proc_macro2::Literal::usize_unsuffixed(slot);
+            let len = model.buffer_len(k);
+            let output = model.layer_output(k, quote! {output});
+            quote! {
+                let output: &[#float_type; {#len}] = (&buffers[#slot][..#len]).try_into()?;
+                Ok(*#output)
+            }
+        }
+        [k] => {
+            let output = saved_output(k);
+            quote! {Ok(*#output)}
+        }
+        _ => {
+            let ranges = model.output_ranges();
+            let saved = outputs.iter().map(|&k| saved_output(k));
+            quote! {
+                let mut output: [#float_type; #output_len] = [0.; #output_len];
+                #(
+                    output[#ranges].copy_from_slice(#saved);
+                )*
+                Ok(output)
+            }
+        }
+    };
 
     quote! {
-        fn predict(&mut self, i: impl exotic::slas::prelude::StaticVec<#float_type, #input_len>, o: &mut impl StaticVec<#float_type, #cache_len>)
-        -> Result<()>{
-            #(
-                self.#layer_names.predict(#layer_inputs, #buffer_output)?;
-                let #layer_names = &*#buffer_output;
-            )*
-            Ok(())
+        /// Predict the output of the model, without keeping the output of every layer.
+        /// Only the outputs that are needed by later layers are kept, so this needs a lot less memory than `predict`.
+        #vis fn infer(&mut self, i: impl StaticVec<#float_type, #input_len>) -> Result<[#float_type; #output_len]>{
+            #shared_input
+            #buffers
+            #(#saved)*
+            #(#steps)*
+            #ret
         }
     }
 }
 
-fn backprop(model: &Model) -> TokenStream2 {
+/// Functions concatenating the named inputs of the model into its input,
+/// and the gradients of its outputs into the gradient of its output.
+fn concat_helpers(model: &Model) -> TokenStream2 {
     let Model {
+        vis,
         float_type,
         input_len,
         output_len,
+        layers,
+        named_inputs,
+        outputs,
+        ..
+    } = model;
+
+    let input = if named_inputs.is_empty() {
+        quote! {}
+    } else {
+        let names: Vec<_> = named_inputs.iter().map(|i| &i.name).collect();
+        let lens = named_inputs.iter().map(|i| &i.len);
+        let ranges = (0..named_inputs.len()).map(|k| model.input_range(k));
+        quote! {
+            /// Concatenate the named inputs of the model into its input.
+            #vis fn input(#(#names: impl StaticVec<#float_type, #lens>),*) -> [#float_type; #input_len]{
+                let mut concat: [#float_type; #input_len] = [0.; #input_len];
+                #(
+                    concat[#ranges].copy_from_slice(#names.moo_ref());
+                )*
+                concat
+            }
+        }
+    };
+
+    let output_gradient = if outputs.len() == 1 {
+        quote! {}
+    } else {
+        let names: Vec<_> = outputs.iter().map(|&k| &layers[k].name).collect();
+        let types = outputs.iter().map(|&k| &layers[k].ty);
+        let ranges = model.output_ranges();
+        quote! {
+            /// Concatenate the gradients of every output of the model, so they can be passed to `backpropagate`.
+            #vis fn output_gradient(#(#names: impl StaticVec<#float_type, {<#types>::O_LEN}>),*) -> [#float_type; #output_len]{
+                let mut concat: [#float_type; #output_len] = [0.; #output_len];
+                #(
+                    concat[#ranges].copy_from_slice(#names.moo_ref());
+                )*
+                concat
+            }
+        }
+    };
+
+    quote! {
+        #input
+        #output_gradient
+    }
+}
+
+/// A struct holding the buffer of every layer, which can be passed to `predict` and `backpropagate` as the buffer.
+fn cache(model: &Model) -> TokenStream2 {
+    let Model {
+        vis,
+        name,
+        float_type,
+        output_len,
         cache_len,
         layers,
+        outputs,
         ..
     } = model;
 
-    let layer_names: Vec<_> = layer_names(layers.0.len()).iter().cloned().rev().collect();
-
-    let layer_inputs: Vec<_> = (0..layers.0.len())
-        .rev()
-        .map(|n| {
-            if n == 0{return quote!{i}}
-            let ofset = if n == 0{quote!{0}}else{
-                let lt: Vec<_> = (0..n-1)
-                    .map(|n| {
-                        let t = &layers.0[n];
-                        quote! {#t::O_LEN}
-                    })
-                    .collect();
+    let cache_name = format_ident!("{}Cache", name);
+    let layout = model.layout();
+    let fields: Vec<_> = layout.iter().map(|&k| &layers[k].name).collect();
+    let lens: Vec<_> = layout.iter().map(|&k| model.buffer_len(k)).collect();
+    let field_output = |k: usize| {
+        let field = &layers[k].name;
+        model.layer_output(k, quote! {&self.#field})
+    };
 
-                quote! {{#(#lt +)* 0 }}
-            };
+    // With several outputs, their concatenation is stored after the buffers of the layers.
+    let (output_field, output_init, output) = match outputs[..] {
+        [k] => (quote! {}, quote! {}, field_output(k)),
+        _ => (
+            quote! { #vis model_output: [#float_type; #output_len], },
+            quote! { model_output: [0.; #output_len], },
+            quote! {&self.model_output},
+        ),
+    };
+
+    let index = (0..layers.len()).map(proc_macro2::Literal::usize_unsuffixed);
+    let layer_types = layers.iter().map(|l| {
+        let ty = &l.ty;
+        quote! {[#float_type; {<#ty>::O_LEN}]}
+    });
+    let layer_outputs = (0..layers.len()).map(field_output);
+    let doc = format!("The buffer of every layer of [`{name}`], holding their outputs.");
+
+    quote! {
+        #[doc = #doc]
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        #vis struct #cache_name{
+            #(
+                #vis #fields: [#float_type; {#lens}],
+            )*
+            #output_field
+        }
+
+        #[allow(dead_code)]
+        impl #cache_name{
+            #vis fn new() -> Self{
+                Self{#(
+                    #fields: [0.; {#lens}],
+                )*
+                #output_init}
+            }
+
+            /// The output of the model, which is the concatenated output of its output layers.
+            #vis fn output(&self) -> &[#float_type; #output_len]{
+                #output
+            }
 
-            let layer = &layers.0[n];
-            quote! { unsafe{ std::mem::transmute::<_, StaticVecRef::<#float_type, {#layer::I_LEN}>>(buffer.as_ptr().add(#ofset)) } }
+            /// The output of layer `N`.
+            #vis fn layer<const N: usize>(&self) -> &<Self as LayerOutput<N>>::Output
+            where
+                Self: LayerOutput<N>,
+            {
+                <Self as LayerOutput<N>>::layer_output(self)
+            }
+        }
+
+        impl Default for #cache_name{
+            fn default() -> Self{
+                Self::new()
+            }
+        }
+
+        // The fields are all arrays of the same float type, so with `repr(C)` they are laid out contiguously.
+        impl StaticVec<#float_type, #cache_len> for #cache_name{
+            fn as_ptr(&self) -> *const #float_type{
+                self as *const Self as *const #float_type
+            }
+
+            fn as_mut_ptr(&mut self) -> *mut #float_type{
+                self as *mut Self as *mut #float_type
+            }
+        }
+
+        #(
+            impl LayerOutput<#index> for #cache_name{
+                type Output = #layer_types;
+
+                fn layer_output(&self) -> &Self::Output{
+                    #layer_outputs
+                }
+            }
+        )*
+    }
+}
+
+fn batch(model: &Model) -> TokenStream2 {
+    let Model {
+        vis,
+        name,
+        float_type,
+        input_len,
+        output_len,
+        layers,
+        inputs,
+        outputs: model_outputs,
+        order,
+        ..
+    } = model;
+
+    let layer_names = layer_names(layers.len());
+    let fields: Vec<_> = layers.iter().map(|l| &l.name).collect();
+    let buffer_name = format_ident!("{}BatchBuffer", name);
+    let batch_layer: Vec<_> = layers
+        .iter()
+        .map(|l| {
+            let t = &l.ty;
+            quote! { <#t as BatchLayer<#float_type, {<#t>::I_LEN}, {<#t>::O_LEN}, BATCH>> }
         })
         .collect();
+    let batch_output = |k: usize| {
+        let (layer, field) = (&batch_layer[k], fields[k]);
+        quote! {#layer::batch_output(&buffer.#field)}
+    };
 
-    let layer_deltas: Vec<_> = (0..layers.0.len())
-        .rev()
-        .map(|n| {
-            if n == layers.0.len() - 1 {
-                format_ident!("gradient")
-            } else {
-                let n = n + 1;
-                format_ident!("l{n}")
+    let access = |node| {
+        model.access(node, quote! {i[b]}, |k| {
+            let output = batch_output(k);
+            quote! {#output[b]}
+        })
+    };
+    let gradient = |node| {
+        let d = gradient_name(node);
+        model.access(node, quote! {#d[b]}, |_| quote! {#d[b]})
+    };
+
+    // The merged inputs of every layer, and the input that is passed to it.
+    let merged: Vec<_> = layers
+        .iter()
+        .zip(inputs)
+        .enumerate()
+        .map(|(n, (layer, input))| match input {
+            LayerInput::Previous if n == 0 => (quote! {}, quote! {i}),
+            LayerInput::Previous => (quote! {}, batch_output(n - 1)),
+            LayerInput::Concat(merges) => {
+                let ty = &layer.ty;
+                let fill = model.fill_input(merges, quote! {x}, &access);
+                (
+                    quote! {
+                        let x = {
+                            let mut x: [[#float_type; {<#ty>::I_LEN}]; BATCH] = [[0.; {<#ty>::I_LEN}]; BATCH];
+                            for (b, x) in x.iter_mut().enumerate() {
+                                #fill
+                            }
+                            x
+                        };
+                    },
+                    quote! {&x},
+                )
             }
         })
         .collect();
 
-    let ret = format_ident!("l0");
+    let output_ranges = model.output_ranges();
+
+    // With more than one output, the outputs are concatenated into an extra buffer.
+    let (output_field, output_init, output, concat_output) = match model_outputs[..] {
+        [k] => (quote! {}, quote! {}, batch_output(k), quote! {}),
+        _ => {
+            let output_layers = model_outputs.iter().map(|&k| batch_output(k));
+            (
+                quote! { model_output: [[#float_type; #output_len]; BATCH], },
+                quote! { model_output: [[0.; #output_len]; BATCH], },
+                quote! {&buffer.model_output},
+                quote! {
+                    for b in 0..BATCH {
+                        #(
+                            buffer.model_output[b][#output_ranges].copy_from_slice(&#output_layers[b]);
+                        )*
+                    }
+                },
+            )
+        }
+    };
+
+    let predict_steps = order.iter().map(|&n| {
+        let (layer, field, (x, i)) = (model.layer(n), fields[n], &merged[n]);
+        quote! {
+            #x
+            #layer.predict_batch(#i, &mut buffer.#field)?;
+        }
+    });
+
+    let input_sum = if model.is_direct(Node::Input) {
+        quote! {}
+    } else {
+        quote! {
+            let mut di: [[#float_type; #input_len]; BATCH] = [[0.; #input_len]; BATCH];
+        }
+    };
+    let sums = order
+        .iter()
+        .filter(|&&k| model.gradient_source(k) == GradientSource::Summed)
+        .map(|&k| {
+            let (d, len) = (
+                gradient_name(Node::Layer(k)),
+                model.node_len(Node::Layer(k)),
+            );
+            let init = match model_outputs.iter().position(|&o| o == k) {
+                Some(n) => {
+                    let range = &output_ranges[n];
+                    quote! {
+                        for b in 0..BATCH {
+                            #d[b].copy_from_slice(&gradient[b][#range]);
+                        }
+                    }
+                }
+                None => quote! {},
+            };
+            quote! {
+                let mut #d: [[#float_type; #len]; BATCH] = [[0.; #len]; BATCH];
+                #init
+            }
+        });
+
+    let backprop_steps = order.iter().rev().map(|&n| {
+        let (layer, field, l, (x, i)) = (model.layer(n), fields[n], &layer_names[n], &merged[n]);
+        let delta = match model.gradient_source(n) {
+            GradientSource::Given => quote! {gradient},
+            GradientSource::Next => {
+                let next = &layer_names[n + 1];
+                quote! {&#next}
+            }
+            GradientSource::Summed => {
+                let d = gradient_name(Node::Layer(n));
+                quote! {&#d}
+            }
+        };
+
+        let route = match &inputs[n] {
+            LayerInput::Previous => {
+                let prev = if n == 0 {
+                    Node::Input
+                } else {
+                    Node::Layer(n - 1)
+                };
+                if model.is_direct(prev) {
+                    quote! {}
+                } else {
+                    let (d, len) = (gradient_name(prev), model.node_len(prev));
+                    quote! {
+                        for b in 0..BATCH {
+                            for j in 0..#len {
+                                #d[b][j] += #l[b][j];
+                            }
+                        }
+                    }
+                }
+            }
+            LayerInput::Concat(merges) => {
+                let route = model.route_gradient(merges, &access, &gradient);
+                quote! {
+                    for b in 0..BATCH {
+                        let g = &#l[b];
+                        #route
+                    }
+                }
+            }
+        };
+
+        quote! {
+            #x
+            let #l = #layer.backpropagate_batch(#i, &buffer.#field, #delta)?;
+            #route
+        }
+    });
+
+    let (accumulate_shared, update_shared) = model.shared_updates();
+
+    let ret = if model.is_direct(Node::Input) {
+        format_ident!("l0")
+    } else {
+        gradient_name(Node::Input)
+    };
 
     quote! {
-        fn backpropagate(&mut self, mut i: impl exotic::slas::prelude::StaticVec<#float_type, #input_len>, buffer: &impl exotic::slas::prelude::StaticVec<#float_type, #cache_len>, gradient: impl exotic::slas::prelude::StaticVec<#float_type, #output_len>) -> Result<[#float_type; #input_len]>{
+        #vis struct #buffer_name<const BATCH: usize>{
             #(
-                let #layer_names = self.#layer_names.backpropagate(#layer_inputs, &unsafe{NullVec::new()}, #layer_deltas)?;
+                #fields: #batch_layer::BatchBuffer,
             )*
+            #output_field
+        }
 
-            Ok(#ret)
+        impl<const BATCH: usize> BatchLayer<#float_type, #input_len, #output_len, BATCH> for #name{
+            type BatchBuffer = #buffer_name<BATCH>;
+
+            fn batch_buffer() -> Self::BatchBuffer{
+                #buffer_name{#(
+                    #fields: #batch_layer::batch_buffer(),
+                )*
+                #output_init}
+            }
+
+            fn batch_output(buffer: &Self::BatchBuffer) -> &[[#float_type; #output_len]; BATCH]{
+                #output
+            }
+
+            fn predict_batch(&mut self, i: &[[#float_type; #input_len]; BATCH], buffer: &mut Self::BatchBuffer) -> Result<()>{
+                #(#predict_steps)*
+                #concat_output
+                Ok(())
+            }
+
+            fn backpropagate_batch(&mut self, i: &[[#float_type; #input_len]; BATCH], buffer: &Self::BatchBuffer, gradient: &[[#float_type; #output_len]; BATCH]) -> Result<[[#float_type; #input_len]; BATCH]>{
+                #input_sum
+                #(#sums)*
+                #accumulate_shared
+                #(#backprop_steps)*
+                #update_shared
+                Ok(#ret)
+            }
         }
     }
 }
 
+/// The parameters of a model are those of its fields, named by the field followed by a `.`.
+fn parameters(model: &Model) -> TokenStream2 {
+    let Model {
+        name,
+        float_type,
+        layers,
+        ..
+    } = model;
+
+    let fields = model.fields();
+    let types = layers.iter().filter(|l| l.shares.is_none()).map(|l| &l.ty);
+    let prefixes: Vec<_> = fields.iter().map(|field| format!("{field}.")).collect();
+
+    quote! {
+        impl Parameters<#float_type> for #name{
+            const PARAM_COUNT: usize = #(<#types as Parameters<#float_type>>::PARAM_COUNT)+*;
+
+            fn visit_params(&self, visitor: &mut impl FnMut(&str, &[#float_type])) {
+                #(
+                    self.#fields.visit_params(&mut |name, params| visitor(&(#prefixes.to_string() + name), params));
+                )*
+            }
+
+            fn visit_params_mut(&mut self, visitor: &mut impl FnMut(&str, &mut [#float_type])) {
+                #(
+                    self.#fields.visit_params_mut(&mut |name, params| visitor(&(#prefixes.to_string() + name), params));
+                )*
+            }
+
+            fn visit_grads(&self, visitor: &mut impl FnMut(&str, &[#float_type])) {
+                #(
+                    self.#fields.visit_grads(&mut |name, grads| visitor(&(#prefixes.to_string() + name), grads));
+                )*
+            }
+        }
+    }
+}
+
+/// A model is serialized as its fields, in order.
+/// `save` and `load` add a header with the shapes of the fields, so a file can only be loaded into the same model.
+fn serialization(model: &Model) -> TokenStream2 {
+    let Model {
+        vis,
+        name,
+        float_type,
+        layers,
+        ..
+    } = model;
+
+    let fields = model.fields();
+    let names = fields.iter().map(|field| field.to_string());
+    let types = layers.iter().filter(|l| l.shares.is_none()).map(|l| &l.ty);
+    let shapes = quote! {
+        &[#(
+            exotic::serialization::LayerShape{
+                name: #names,
+                i_len: <#types>::I_LEN,
+                o_len: <#types>::O_LEN,
+                param_count: <#types as Parameters<#float_type>>::PARAM_COUNT,
+            },
+        )*]
+    };
+
+    quote! {
+        impl Serialization for #name{
+            fn serialize_into(&self, writer: &mut dyn std::io::Write) -> Result<()> {
+                #(self.#fields.serialize_into(writer)?;)*
+                Ok(())
+            }
+
+            fn deserialize_from(&mut self, reader: &mut dyn std::io::Read) -> Result<()> {
+                #(self.#fields.deserialize_from(reader)?;)*
+                Ok(())
+            }
+        }
+
+        #[allow(dead_code)]
+        impl #name{
+            /// Write the parameters and optimizer state of the model, in the format of `exotic::serialization::save`.
+            #vis fn save(&self, writer: &mut impl std::io::Write) -> Result<()> {
+                exotic::serialization::save::<#float_type>(self, #shapes, writer)
+            }
+
+            /// Read a model written by `save`. Fails without changing the model if the shapes of its layers don't match.
+            #vis fn load(&mut self, reader: &mut impl std::io::Read) -> Result<()> {
+                exotic::serialization::load::<#float_type>(self, #shapes, reader)
+            }
+        }
+    }
+}
+
+/// Define a model as a struct of layers, that are run one after the other.
+/// ```ignore
+/// model! {
+///     #[derive(Clone)]
+///     pub struct Net {
+///         l0: DenseLayer<f32, Blas, 4, 2> = DenseLayer::random(Sgd::new(0.1)),
+///         l1: Softmax<f32, 2>,
+///     }
+/// }
+/// ```
+/// Layers without an initializer are created with `Default::default()`.
+/// Layers marked `#[frozen]` start out frozen, so `backpropagate` passes the gradient through them without updating them.
+/// They can be unfrozen later with `net.l0.set_frozen(false)`.
+///
+/// The model only implements `Layer` by default, so any layer can be used in it.
+/// The other traits are implemented when the model is marked with their attribute,
+/// in which case every layer must implement them as well.
+/// ```ignore
+/// model! {
+///     #[batch]
+///     #[parameters]
+///     #[serialize]
+///     pub struct Net {
+///         l0: DenseLayer<f32, Blas, 4, 2> = DenseLayer::random(Sgd::new(0.1)),
+///         l1: Tanh<f32, 2>,
+///     }
+/// }
+/// ```
+/// `#[batch]` implements `BatchLayer`, for any batch size.
+/// Custom layers can get a `BatchLayer` that runs the samples one at a time, from `SampleLayer` and `exotic::sample_batch_layer!`.
+///
+/// `#[parameters]` implements `Parameters`, which names the parameters of its layers by their path, like `l0.weights`.
+/// It's needed to `gradcheck` the model.
+///
+/// `#[serialize]` implements `Serialization`, and adds `net.save(&mut writer)`,
+/// which writes the parameters and optimizer state of every layer in a versioned binary format,
+/// and `net.load(&mut reader)`, which reads them back after checking that the saved layers have the same shapes.
+/// The shapes include the parameter counts, so the layers must implement `Parameters` too.
+/// Nested models are saved as part of the outer model.
+///
+/// The float type and the input and output length of the model are taken from its first and last layer.
+/// They can also be written out, as `pub struct Net: Layer<f32, 4, 2>`, in which case they are checked against the layers.
+///
+/// By default a layer takes the output of the previous layer as its input,
+/// but it can also list its inputs in parentheses after its name, for residual and skip connections.
+/// Inputs separated by `,` are concatenated, and inputs joined by `+` or `*` are added or multiplied element-wise.
+/// `input` is the input of the model.
+/// ```ignore
+/// model! {
+///     struct Net: Layer<f32, 4, 2> {
+///         l0: DenseLayer<f32, Blas, 4, 4> = DenseLayer::random(Sgd::new(0.1)),
+///         l1(l0 + input): Tanh<f32, 4>,
+///         l2(l1, input): DenseLayer<f32, Blas, 8, 2> = DenseLayer::random(Sgd::new(0.1)),
+///     }
+/// }
+/// ```
+/// The gradients of a layer whose output is used more than once are summed in `backpropagate`.
+/// The signature must be written out, when the first layer has a list of inputs.
+///
+/// A model can also have several named inputs, and several output layers.
+/// The named inputs are concatenated into the input of the model, and can be used as inputs of the layers.
+/// The outputs are concatenated into the output of the model, and are stored last in the cache.
+/// ```ignore
+/// model! {
+///     struct Net(image: 16, meta: 2) -> (class, value) {
+///         l0(image): DenseLayer<f32, Blas, 16, 8> = DenseLayer::random(Sgd::new(0.1)),
+///         l1(l0, meta): DenseLayer<f32, Blas, 10, 8> = DenseLayer::random(Sgd::new(0.1)),
+///         class(l1): DenseLayer<f32, Blas, 8, 4> = DenseLayer::random(Sgd::new(0.1)),
+///         value(l1): DenseLayer<f32, Blas, 8, 1> = DenseLayer::random(Sgd::new(0.1)),
+///     }
+/// }
+/// ```
+/// `Net::input(image, meta)` and `Net::output_gradient(class, value)` concatenate the inputs and the gradients of the outputs.
+///
+/// Layers can take input from layers defined after them, as long as no layer depends on its own output.
+/// They are run in an order where every layer runs after its inputs.
+///
+/// A model is itself a layer, so it can be used in another model.
+/// Every layer gets its whole buffer in the cache, so the cache of the inner model is part of the cache of the outer model.
+/// ```ignore
+/// model! {
+///     struct Block {
+///         l0: DenseLayer<f32, Blas, 8, 8> = DenseLayer::random(Sgd::new(0.1)),
+///         l1(l0 + input): Tanh<f32, 8>,
+///     }
+/// }
+///
+/// model! {
+///     struct Net: Layer<f32, 8, 2> {
+///         b0: Block,
+///         b1: Block,
+///         l2: DenseLayer<f32, Blas, 8, 2> = DenseLayer::random(Sgd::new(0.1)),
+///     }
+/// }
+/// ```
+///
+/// A layer can use the parameters of another layer, by naming it instead of giving a type,
+/// or run it backwards with `.transposed()`, for layers that implement `Transpose`.
+/// Such a layer isn't a field of the model, but it gets its own input and its own place in the cache.
+/// ```ignore
+/// model! {
+///     struct Net(left: 4, right: 4) -> out {
+///         encode(left): DenseLayer<f32, Blas, 4, 2> = DenseLayer::random(Sgd::new(0.1)),
+///         encode_right(right) = encode,
+///         out(encode + encode_right) = encode.transposed(),
+///     }
+/// }
+/// ```
+/// The gradients of every use of the parameters are summed in `backpropagate`, before they are applied.
 #[proc_macro]
 pub fn model(input: TokenStream) -> TokenStream {
-    let model = match from_str::<ModelRon>(&input.to_string()) {
-        Err(e) => bail!("Error parsing macro input: {}", e),
-        Ok(ok) => ok,
-    }
-    .to_model();
-
-    let layer_names = layer_names(model.layers.0.len());
-    let model_name = model.name.clone();
-    let derive = model.derive.clone();
-    let layer_types = model.layers.0.clone();
-    let layer_init = model.layers.1.clone();
-    let float_type = model.float_type.clone();
-    let cache_len = model.cache_len.clone();
-    let input_len = model.input_len;
-    let output_len = model.output_len;
+    let model = parse_macro_input!(input as Model);
+
+    let Model {
+        attrs,
+        vis,
+        name: model_name,
+        float_type,
+        input_len,
+        output_len,
+        cache_len,
+        layers,
+        ..
+    } = &model;
+
+    let own_layers: Vec<_> = layers.iter().filter(|l| l.shares.is_none()).collect();
+    let layer_attrs = own_layers.iter().map(|l| &l.attrs);
+    let layer_vis = own_layers.iter().map(|l| &l.vis);
+    let fields = model.fields();
+    let layer_types = own_layers.iter().map(|l| &l.ty);
+    let layer_init = own_layers.iter().map(|l| match &l.init {
+        Some(init) => init.to_token_stream(),
+        None => quote! {Default::default()},
+    });
+    let init = quote! {
+        Self{#(
+            #fields : #layer_init,
+        )*}
+    };
+    let frozen: Vec<_> = own_layers
+        .iter()
+        .filter(|l| l.frozen)
+        .map(|l| &l.name)
+        .collect();
+    let new = if frozen.is_empty() {
+        init
+    } else {
+        quote! {
+            let mut model = #init;
+            #(model.#frozen.set_frozen(true);)*
+            model
+        }
+    };
 
     let def = quote! {
-        #[derive(#(#derive),*)]
-        struct #model_name{
+        #(#attrs)*
+        #vis struct #model_name{
             #(
-                #layer_names: #layer_types,
+                #(#layer_attrs)*
+                #layer_vis #fields: #layer_types,
             )*
         }
     };
 
     let predict = predict(&model);
     let backprop = backprop(&model);
+    let cache = cache(&model);
+    let infer = infer(&model);
+    let concat_helpers = concat_helpers(&model);
+    let assertions = assertions(&model);
+    let batch = model.batch.then(|| batch(&model));
+    let parameters = model.parameters.then(|| parameters(&model));
+    let serialization = model.serialize.then(|| serialization(&model));
 
     let impl_model = quote! {
         impl Layer<#float_type, #input_len, #output_len, #cache_len> for #model_name{
@@ -248,25 +2037,57 @@ pub fn model(input: TokenStream) -> TokenStream {
 
             #predict
             #backprop
+
+            fn set_lr(&mut self, lr: #float_type) {
+                #(self.#fields.set_lr(lr);)*
+            }
+
+            fn set_accumulate(&mut self, accumulate: bool) {
+                #(self.#fields.set_accumulate(accumulate);)*
+            }
+
+            fn accumulating(&self) -> bool {
+                #(self.#fields.accumulating())||*
+            }
+
+            fn apply_gradients(&mut self) {
+                #(self.#fields.apply_gradients();)*
+            }
+
+            fn set_frozen(&mut self, frozen: bool) {
+                #(self.#fields.set_frozen(frozen);)*
+            }
+
+            fn zero_grad(&mut self) {
+                #(self.#fields.zero_grad();)*
+            }
         }
 
+        #[allow(dead_code)]
         impl #model_name{
-            fn new() -> Self{
-                fn default<T: Default>()->T{T::default()}
-                Self{#(
-                    #layer_names : #layer_init,
-                )*}
+            #vis fn new() -> Self{
+                #new
             }
 
-            unsafe fn uninit_cache() -> [#float_type; #cache_len]{
-                unsafe{ core::mem::MaybeUninit::uninit().assume_init() }
+            #infer
+            #concat_helpers
+        }
+
+        impl Default for #model_name{
+            fn default() -> Self{
+                Self::new()
             }
         }
     };
 
     quote! {
         #def
+        #assertions
         #impl_model
+        #cache
+        #batch
+        #parameters
+        #serialization
     }
     .into()
 }
@@ -1,4 +1,3 @@
-
 #![feature(generic_arg_infer)]
 #[cfg(test)]
 mod test {
@@ -57,41 +56,33 @@ mod test {
 
     #[test]
     fn basic_with_macro() -> Result<()> {
-        model! {(
-            derive: [Copy, Clone],
-            name: "MacroNet",
-            layers: [
-                ("DenseLayer::<f32, Blas, 4, 2>", "DenseLayer::random(0.1)"),
-                ("Softmax::<f32, 2>", "default()")
-            ],
-            float_type: "f32",
-            input_len: 4,
-            output_len: 2
-        )}
+        model! {
+            #[derive(Copy, Clone)]
+            struct MacroNet {
+                l0: DenseLayer<f32, Blas, 4, 2> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Softmax<f32, 2>,
+            }
+        }
 
         let mut net = MacroNet::new();
 
         let y = moo![f32: 0, 1];
         let i = moo![f32: 0..4];
-        let mut buffer = unsafe { MacroNet::uninit_cache() };
+        let mut buffer = MacroNetCache::new();
 
         for _ in 0..2000 {
             net.predict(&i, &mut buffer)?;
-            let o = &buffer[buffer.len() - 2..buffer.len()];
+            let (_, dy) = Mse.loss(buffer.output(), &y);
 
-            let dy = moo![|n| o[n] - y[n]; 2];
-
-            net.backpropagate(&i, &mut buffer, dy)?;
+            net.backpropagate(&i, &buffer, dy)?;
         }
 
         net.predict(&i, &mut buffer)?;
-        let o = &buffer[buffer.len() - 2..buffer.len()];
-        let cost = o
-            .iter()
-            .zip(y.iter())
-            .map(|(o, y)| (o - y).powi_(2))
-            .sum::<f32>()
-            .abs();
+        let o = buffer.output();
+        let (cost, _) = Mse.loss(o, &y);
+        assert_eq!(buffer.layer::<1>(), o);
+        assert_eq!(MacroNet::output(&buffer), o);
+        assert_eq!(&net.infer(&i)?, o);
 
         assert!(cost < 0.01, "Found {o:?}, expecteed {y:?} (cost: {cost})");
 
@@ -100,35 +91,32 @@ mod test {
 
     #[test]
     fn basic_long_with_macro() -> Result<()> {
-        model! {(
-            derive: [Copy, Clone],
-            name: "MacroNet",
-            layers: [
-                ("DenseLayer::<f32, Blas, 4, 2>", "DenseLayer::random(0.001)"),
-                ("Tanh::<f32, 2>", "default()"),
-                ("DenseLayer::<f32, Blas, 2, 4>", "DenseLayer::random(0.001)"),
-                ("Tanh::<f32, 4>", "default()")
-            ],
-            float_type: "f32",
-            input_len: 4,
-            output_len: 4
-        )}
+        model! {
+            #[derive(Copy, Clone)]
+            struct MacroNet: Layer<f32, 4, 4> {
+                l0: DenseLayer<f32, Blas, 4, 2> = DenseLayer::random(Sgd::new(0.001)),
+                l1: Tanh<f32, 2>,
+                l2: DenseLayer<f32, Blas, 2, 4> = DenseLayer::random(Sgd::new(0.001)),
+                l3: Tanh<f32, 4>,
+            }
+        }
 
         let mut net = MacroNet::new();
 
-        let mut buffer = unsafe { MacroNet::uninit_cache() };
+        let mut buffer = MacroNetCache::new();
 
         for epoch in 0..20000 {
             net.predict(&moo![f32: epoch % 5, 0, 0, 0], &mut buffer)?;
-            let o = &buffer[buffer.len() - 4..buffer.len()];
+            let o = buffer.output();
 
             let dy = moo![|n| o[n] - ((n%2) * (epoch%5)) as f32 ; 4];
 
-            net.backpropagate(&moo![f32: epoch % 5, 0, 0, 0], &mut buffer, dy)?;
+            net.backpropagate(&moo![f32: epoch % 5, 0, 0, 0], &buffer, dy)?;
         }
 
         net.predict(moo![f32: 1, 0, 0, 0], &mut buffer)?;
-        let o = &buffer[buffer.len() - 4..buffer.len()];
+        let o = buffer.output();
+        assert_eq!(&net.infer(moo![f32: 1, 0, 0, 0])?, o);
         let cost = o
             .iter()
             .zip(moo![f32: 0, 1, 0, 1].iter())
@@ -143,4 +131,932 @@ mod test {
 
         Ok(())
     }
+
+    /// The checks shared by the model tests:
+    /// the gradients of `backpropagate` agree with `gradcheck`, `infer` agrees with `predict`,
+    /// and a batch of `i` and `j` agrees with predicting and backpropagating them one at a time.
+    /// `infer` is generated by `model!` instead of being part of `Layer`, so it's passed as a closure.
+    fn check_model<L, const I_LEN: usize, const O_LEN: usize, const BUFFER_LEN: usize>(
+        net: &L,
+        i: [f64; I_LEN],
+        j: [f64; I_LEN],
+        g: [f64; O_LEN],
+        infer: impl FnOnce(&mut L, &[f64; I_LEN]) -> Result<[f64; O_LEN]>,
+    ) -> Result<()>
+    where
+        L: Layer<f64, I_LEN, O_LEN, BUFFER_LEN>
+            + BatchLayer<f64, I_LEN, O_LEN, 2>
+            + Parameters<f64>
+            + Clone,
+    {
+        let check = gradcheck(net, &i, &g, 1e-5)?;
+        assert!(check.max_error < 1e-6, "{check:?}");
+
+        let mut buffer = [0.; BUFFER_LEN];
+        net.clone().predict(&i, &mut buffer)?;
+        assert_eq!(&infer(&mut net.clone(), &i)?, L::output(&buffer));
+
+        let mut batch = <L as BatchLayer<f64, I_LEN, O_LEN, 2>>::batch_buffer();
+        net.clone().predict_batch(&[i, j], &mut batch)?;
+        let batch_gradient = net.clone().backpropagate_batch(&[i, j], &batch, &[g, g])?;
+
+        for (n, i) in [i, j].iter().enumerate() {
+            net.clone().predict(i, &mut buffer)?;
+            let gradient = net.clone().backpropagate(i, &buffer, g)?;
+            for (a, b) in batch_gradient[n].iter().zip(gradient.moo_ref()) {
+                assert!((a - b).abs() < 1e-9);
+            }
+            assert_eq!(&L::batch_output(&batch)[n], L::output(&buffer));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_connections() -> Result<()> {
+        model! {
+            #[batch]
+            #[parameters]
+            #[derive(Clone)]
+            struct SkipNet: Layer<f64, 4, 3> {
+                l0: DenseLayer<f64, Blas, 4, 4> = DenseLayer::random(Sgd::new(0.1)),
+                l1(l0 + input): Tanh<f64, 4>,
+                l2(l1, input): DenseLayer<f64, Blas, 8, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l3: Tanh<f64, 3>,
+            }
+        }
+
+        let net = SkipNet::new();
+        let (i, j) = ([0.5, -1., 0.25, 2.], [-0.3, 0.8, 1.5, -2.]);
+        let g = [1., -0.5, 2.];
+
+        // The input is used by three layers, so its gradient has to be summed correctly.
+        check_model(&net, i, j, g, |net, i| net.infer(i))
+    }
+
+    #[test]
+    fn graph_model() -> Result<()> {
+        model! {
+            #[batch]
+            #[parameters]
+            #[derive(Clone)]
+            struct TwoHeads(image: 4, meta: 2) -> (class, value) {
+                // Defined before `hidden`, but run after it.
+                class(hidden): DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l0(image): DenseLayer<f64, Blas, 4, 4> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Tanh<f64, 4>,
+                gate(meta): DenseLayer<f64, Blas, 2, 4> = DenseLayer::random(Sgd::new(0.1)),
+                hidden(l1 * gate + l0): Tanh<f64, 4>,
+                value(hidden, meta): DenseLayer<f64, Blas, 6, 1> = DenseLayer::random(Sgd::new(0.1)),
+            }
+        }
+
+        let mut net = TwoHeads::new();
+        let i = TwoHeads::input([0.5, -1., 0.25, 2.], [1., -0.5]);
+        let j = TwoHeads::input([-0.3, 0.8, 1.5, -2.], [0.2, 0.7]);
+        let g = TwoHeads::output_gradient([1., -0.5, 2.], [0.7]);
+
+        let mut buffer = TwoHeadsCache::new();
+        net.predict(&i, &mut buffer)?;
+        assert_eq!(buffer.output()[..3], buffer.class);
+        assert_eq!(buffer.output()[3..], buffer.value);
+
+        check_model(&net, i, j, g, |net, i| net.infer(i))
+    }
+
+    #[test]
+    fn nested_models() -> Result<()> {
+        model! {
+            #[batch]
+            #[parameters]
+            #[derive(Clone)]
+            struct Block {
+                l0: DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Tanh<f64, 3>,
+            }
+        }
+
+        model! {
+            #[batch]
+            #[parameters]
+            #[derive(Clone)]
+            struct AutoEncoder: Layer<f64, 4, 3> {
+                encoder: Block,
+                decoder: DenseLayer<f64, Blas, 3, 4> = DenseLayer::random(Sgd::new(0.1)),
+                head(decoder + input): Block,
+            }
+        }
+
+        let mut net = AutoEncoder::new();
+        let (i, j) = ([0.5, -1., 0.25, 2.], [-0.3, 0.8, 1.5, -2.]);
+        let g = [1., -0.5, 2.];
+
+        // The cache of the encoder is embedded in the cache of the model.
+        let mut buffer = AutoEncoderCache::new();
+        net.predict(&i, &mut buffer)?;
+        let mut encoder_buffer = BlockCache::new();
+        net.encoder.clone().predict(&i, &mut encoder_buffer)?;
+        assert_eq!(&buffer.encoder, encoder_buffer.moo_ref());
+        assert_eq!(buffer.layer::<0>(), encoder_buffer.output());
+
+        check_model(&net, i, j, g, |net, i| net.infer(i))
+    }
+
+    #[test]
+    fn tied_layers() -> Result<()> {
+        model! {
+            #[batch]
+            #[parameters]
+            #[derive(Clone)]
+            struct Siamese(left: 4, right: 4) -> out {
+                encode(left): DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Tanh<f64, 3>,
+                encode_right(right) = encode,
+                l3: Tanh<f64, 3>,
+                decode(l1 + l3) = encode.transposed(),
+                out: Tanh<f64, 4>,
+            }
+        }
+
+        let mut net = Siamese::new();
+        let i = Siamese::input([0.5, -1., 0.25, 2.], [1., -0.5, 0.3, 0.1]);
+        let j = Siamese::input([-0.3, 0.8, 1.5, -2.], [0.2, 0.7, -1., 0.4]);
+        let g = [1., -0.5, 2., 0.3];
+
+        let mut buffer = SiameseCache::new();
+        net.predict(&i, &mut buffer)?;
+
+        // The gradients of every use of `encode` are summed, and only applied when the model isn't accumulating.
+        let mut accumulated = net.clone();
+        accumulated.set_accumulate(true);
+        accumulated.backpropagate(&i, &buffer, g)?;
+        assert!(accumulated.accumulating());
+        assert_eq!(accumulated.encode.weights.value, net.encode.weights.value);
+
+        let h = 1e-5;
+        let loss = |net: &mut Siamese| -> Result<f64> {
+            Ok(net.infer(&i)?.iter().zip(g).map(|(o, g)| o * g).sum())
+        };
+        for k in 0..12 {
+            let (mut plus, mut minus) = (net.clone(), net.clone());
+            plus.encode.weights.value[k] += h;
+            minus.encode.weights.value[k] -= h;
+            let numeric = (loss(&mut plus)? - loss(&mut minus)?) / (2. * h);
+            assert!((numeric - accumulated.encode.weights.gradient[k]).abs() < 1e-6);
+        }
+
+        let mut updated = net.clone();
+        updated.backpropagate(&i, &buffer, g)?;
+        assert!(!updated.accumulating());
+        for k in 0..12 {
+            let expected =
+                net.encode.weights.value[k] - 0.1 * accumulated.encode.weights.gradient[k];
+            assert!((updated.encode.weights.value[k] - expected).abs() < 1e-12);
+        }
+
+        check_model(&net, i, j, g, |net, i| net.infer(i))
+    }
+
+    #[test]
+    fn frozen_layers() -> Result<()> {
+        model! {
+            #[batch]
+            #[parameters]
+            #[derive(Clone)]
+            struct FineTune {
+                #[frozen]
+                l0: DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                #[frozen]
+                l1: Prelu<f64, 3> = Prelu::new(Sgd::new(0.1)),
+                l2: DenseLayer<f64, Blas, 3, 2> = DenseLayer::random(Sgd::new(0.1)),
+            }
+        }
+
+        let net = FineTune::new();
+        let (i, j) = ([0.5, -1., 0.25, 2.], [-0.3, 0.8, 1.5, -2.]);
+        let g = [1., -0.5];
+
+        let check = gradcheck(&net, &i, &g, 1e-5)?;
+        assert!(check.max_error < 1e-6, "{check:?}");
+
+        // The gradient is passed through the frozen layers as if they weren't frozen.
+        let mut buffer = FineTuneCache::new();
+        let (mut frozen, mut unfrozen) = (net.clone(), net.clone());
+        unfrozen.set_frozen(false);
+        frozen.predict(&i, &mut buffer)?;
+        let gradient = frozen.backpropagate(&i, &buffer, g)?;
+        assert_eq!(gradient, unfrozen.backpropagate(&i, &buffer, g)?);
+        frozen.apply_gradients();
+
+        assert_eq!(frozen.l0.weights.value, net.l0.weights.value);
+        assert_eq!(frozen.l0.biasies.value, net.l0.biasies.value);
+        assert_eq!(frozen.l1.alpha.value, net.l1.alpha.value);
+        assert_ne!(frozen.l2.weights.value, net.l2.weights.value);
+        assert_ne!(unfrozen.l0.weights.value, net.l0.weights.value);
+
+        let mut batched = net.clone();
+        let mut batch = <FineTune as BatchLayer<f64, 4, 2, 2>>::batch_buffer();
+        batched.predict_batch(&[i, j], &mut batch)?;
+        batched.backpropagate_batch(&[i, j], &batch, &[g, g])?;
+        assert_eq!(batched.l0.weights.value, net.l0.weights.value);
+        assert_ne!(batched.l2.weights.value, net.l2.weights.value);
+
+        frozen.l0.set_frozen(false);
+        frozen.predict(&i, &mut buffer)?;
+        frozen.backpropagate(&i, &buffer, g)?;
+        assert_ne!(frozen.l0.weights.value, net.l0.weights.value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parameter_visitor() -> Result<()> {
+        model! {
+            #[parameters]
+            struct Head {
+                l0: DenseLayer<f64, Blas, 3, 2> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Tanh<f64, 2>,
+            }
+        }
+
+        model! {
+            #[parameters]
+            struct Visited {
+                l0: DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Prelu<f64, 3> = Prelu::new(Sgd::new(0.1)),
+                l2: Tanh<f64, 3>,
+                head: Head,
+            }
+        }
+
+        assert_eq!(Visited::PARAM_COUNT, 4 * 3 + 3 + 3 + 3 * 2 + 2);
+
+        let mut net = Visited::new();
+        let mut names = vec![];
+        let mut count = 0;
+        net.visit_params(&mut |name, params| {
+            names.push(name.to_string());
+            count += params.len();
+        });
+        assert_eq!(
+            names,
+            [
+                "l0.weights",
+                "l0.biasies",
+                "l1.alpha",
+                "head.l0.weights",
+                "head.l0.biasies"
+            ]
+        );
+        assert_eq!(count, Visited::PARAM_COUNT);
+
+        net.visit_params_mut(&mut |_, params| params.iter_mut().for_each(|p| *p = 0.));
+        assert_eq!(net.l1.alpha.value, [0.; 3]);
+        assert_eq!(net.head.l0.weights.value, [0.; 6]);
+        assert_eq!(net.infer(&[0.5, -1., 0.25, 2.])?, [0.; 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        model! {
+            #[parameters]
+            #[serialize]
+            struct Head {
+                l0: DenseLayer<f64, Blas, 3, 2> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Tanh<f64, 2>,
+            }
+        }
+
+        model! {
+            #[serialize]
+            struct Saved {
+                l0: DenseLayer<f64, Blas, 4, 3, Adam<f64>> = DenseLayer::random(Adam::new(0.01)),
+                l1: Prelu<f64, 3> = Prelu::new(Sgd::new(0.1)),
+                head: Head,
+            }
+        }
+
+        model! {
+            #[serialize]
+            struct OtherOptimizer {
+                l0: DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Prelu<f64, 3> = Prelu::new(Sgd::new(0.1)),
+                head: Head,
+            }
+        }
+
+        model! {
+            #[serialize]
+            struct OtherShape {
+                l0: DenseLayer<f64, Blas, 4, 2, Adam<f64>> = DenseLayer::random(Adam::new(0.01)),
+                l1: Prelu<f64, 2> = Prelu::new(Sgd::new(0.1)),
+                head: DenseLayer<f64, Blas, 2, 2> = DenseLayer::random(Sgd::new(0.1)),
+            }
+        }
+
+        let mut net = Saved::new();
+        let i = [0.5, -1., 0.25, 2.];
+        let mut buffer = SavedCache::new();
+        for _ in 0..10 {
+            net.predict(&i, &mut buffer)?;
+            net.backpropagate(&i, &buffer, [1., -0.5])?;
+        }
+
+        let mut bytes = vec![];
+        net.save(&mut bytes)?;
+        assert_eq!(&bytes[..4], b"EXOT");
+
+        let mut loaded = Saved::new();
+        loaded.load(&mut bytes.as_slice())?;
+        assert_eq!(loaded.l0.weights.value, net.l0.weights.value);
+        assert_eq!(loaded.l0.weights.state, net.l0.weights.state);
+        assert_eq!(loaded.l1.alpha.value, net.l1.alpha.value);
+        assert_eq!(loaded.head.l0.biasies.value, net.head.l0.biasies.value);
+        assert_eq!(loaded.infer(&i)?, net.infer(&i)?);
+
+        let mut other = OtherShape::new();
+        let weights = other.l0.weights.value;
+        assert!(other.load(&mut bytes.as_slice()).is_err());
+        assert_eq!(other.l0.weights.value, weights);
+        assert!(OtherOptimizer::new().load(&mut bytes.as_slice()).is_err());
+        assert!(Saved::new().load(&mut &bytes[..bytes.len() - 1]).is_err());
+
+        let mut newer = bytes.clone();
+        newer[4] += 1;
+        assert!(Saved::new().load(&mut newer.as_slice()).is_err());
+
+        // The length of the first layer name follows the magic bytes, the version, the float size and the layer count.
+        let mut corrupt = bytes.clone();
+        corrupt[13..17].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Saved::new().load(&mut corrupt.as_slice()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_layer() -> Result<()> {
+        /// A layer that only implements `Layer`.
+        #[derive(Default)]
+        struct Double;
+
+        impl Layer<f64, 3, 3, 3> for Double {
+            type Gradient = [f64; 3];
+
+            fn predict(
+                &mut self,
+                i: impl StaticVec<f64, 3>,
+                buffer: &mut impl StaticVec<f64, 3>,
+            ) -> Result<()> {
+                for (o, i) in buffer.mut_moo_ref().iter_mut().zip(i.moo_ref()) {
+                    *o = 2. * i;
+                }
+                Ok(())
+            }
+
+            fn backpropagate(
+                &mut self,
+                _: impl StaticVec<f64, 3>,
+                _: &impl StaticVec<f64, 3>,
+                gradient: impl StaticVec<f64, 3>,
+            ) -> Result<[f64; 3]> {
+                Ok(gradient.moo_ref().map(|g| 2. * g))
+            }
+        }
+
+        // Without `#[batch]`, `#[parameters]` or `#[serialize]`, the layers only need to implement `Layer`.
+        model! {
+            struct Custom {
+                l0: DenseLayer<f64, Blas, 4, 3> = DenseLayer::random(Sgd::new(0.1)),
+                l1: Double,
+                l2: Tanh<f64, 3>,
+            }
+        }
+
+        let mut net = Custom::new();
+        let i = [0.5, -1., 0.25, 2.];
+        let mut buffer = CustomCache::new();
+        net.predict(&i, &mut buffer)?;
+        for (o, i) in buffer.l1.iter().zip(buffer.l0.iter()) {
+            assert_eq!(*o, 2. * i);
+        }
+        assert_eq!(&net.infer(&i)?, buffer.output());
+
+        // The gradient is passed through the custom layer, to train the dense layer in front of it.
+        let weights = net.l0.weights.value;
+        net.backpropagate(&i, &buffer, [1., -0.5, 2.])?;
+        assert_ne!(net.l0.weights.value, weights);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dense_with_momentum() -> Result<()> {
+        let mut layer =
+            DenseLayer::<f32, Blas, 4, 2, Nesterov<f32>>::random(Nesterov::new(0.01, 0.9));
+
+        let y = moo![f32: 0, 1];
+        let i = moo![f32: 0..4];
+        let mut o = [0f32; 2];
+
+        for _ in 0..500 {
+            layer.predict(&i, &mut o)?;
+            let dy = moo![|n| o[n] - y[n]; 2];
+            layer.backpropagate(&i, &o, dy)?;
+        }
+
+        layer.predict(&i, &mut o)?;
+        let cost = o
+            .iter()
+            .zip(y.iter())
+            .map(|(o, y)| (o - y).powi_(2))
+            .sum::<f32>();
+
+        assert!(cost < 0.001, "Found {o:?}, expecteed {y:?} (cost: {cost})");
+
+        Ok(())
+    }
+
+    #[test]
+    fn dense_with_adam() -> Result<()> {
+        let mut layer = DenseLayer::<f32, Blas, 4, 2, AdamW<f32>>::random(AdamW::new(0.01, 0.001));
+
+        let y = moo![f32: 0, 1];
+        let i = moo![f32: 0..4];
+        let mut o = [0f32; 2];
+
+        for _ in 0..1000 {
+            layer.predict(&i, &mut o)?;
+            let dy = moo![|n| o[n] - y[n]; 2];
+            layer.backpropagate(&i, &o, dy)?;
+        }
+
+        layer.predict(&i, &mut o)?;
+        let cost = o
+            .iter()
+            .zip(y.iter())
+            .map(|(o, y)| (o - y).powi_(2))
+            .sum::<f32>();
+
+        assert!(cost < 0.001, "Found {o:?}, expecteed {y:?} (cost: {cost})");
+
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_optimizer_state() -> Result<()> {
+        let mut layer = DenseHeapLayer::<f64, Blas, 4, 2, AdaDelta<f64>>::random(AdaDelta::new(1.));
+        let mut copy = DenseHeapLayer::<f64, Blas, 4, 2, AdaDelta<f64>>::random(AdaDelta::new(1.));
+
+        let i = moo![f64: 0..4];
+        let mut o = [0f64; 2];
+
+        for _ in 0..10 {
+            layer.predict(&i, &mut o)?;
+            layer.backpropagate(&i, &o, o)?;
+        }
+
+        let mut bytes = vec![];
+        layer.serialize_into(&mut bytes)?;
+        copy.deserialize_from(&mut bytes.as_slice())?;
+
+        assert_eq!(layer.weights.value, copy.weights.value);
+        assert_eq!(layer.biasies.value, copy.biasies.value);
+        assert_eq!(layer.weights.state, copy.weights.state);
+        assert_eq!(layer.biasies.state, copy.biasies.state);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dense_batch() -> Result<()> {
+        let mut layer = DenseLayer::<f32, Blas, 4, 2>::random(Sgd::new(0.01));
+
+        let i = [moo![f32: 0..4], moo![f32: 4..8], moo![f32: 1, -1, 0, 2]];
+        let y = [[1f32, 0.], [0., 1.], [0.5, 0.5]];
+        let mut buffer = <DenseLayer<f32, Blas, 4, 2> as BatchLayer<f32, 4, 2, 3>>::batch_buffer();
+
+        layer.predict_batch(&i, &mut buffer)?;
+        for (i, o) in i.iter().zip(buffer.iter()) {
+            let mut expected = [0f32; 2];
+            layer.predict(i, &mut expected)?;
+            for (o, e) in o.iter().zip(expected.iter()) {
+                assert!((o - e).abs() < 1e-4, "Found {o}, expected {e}");
+            }
+        }
+
+        for _ in 0..2000 {
+            layer.predict_batch(&i, &mut buffer)?;
+            let dy = [0, 1, 2].map(|b| moo![|n| buffer[b][n] - y[b][n]; 2]);
+            layer.backpropagate_batch(&i, &buffer, &dy)?;
+        }
+
+        layer.predict_batch(&i, &mut buffer)?;
+        let cost = buffer
+            .iter()
+            .zip(y.iter())
+            .flat_map(|(o, y)| o.iter().zip(y.iter()).map(|(o, y)| (o - y).powi_(2)))
+            .sum::<f32>();
+
+        assert!(
+            cost < 0.01,
+            "Found {buffer:?}, expecteed {y:?} (cost: {cost})"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn accumulate_gradients() -> Result<()> {
+        let mut layer = DenseLayer::<f32, Blas, 4, 2>::random(Sgd::new(0.1));
+        layer.set_accumulate(true);
+        let mut batched = layer;
+
+        let i = [moo![f32: 0..4], moo![f32: 4..8], moo![f32: 1, -1, 0, 2]];
+        let weights = layer.weights.value;
+        let mut o = [0f32; 2];
+        let mut dy = [[0f32; 2]; 3];
+
+        for (i, dy) in i.iter().zip(dy.iter_mut()) {
+            layer.predict(i, &mut o)?;
+            *dy = o;
+            layer.backpropagate(i, &o, o)?;
+        }
+        assert_eq!(
+            layer.weights.value, weights,
+            "Weights were updated while accumulating"
+        );
+
+        let mut buffer = <DenseLayer<f32, Blas, 4, 2> as BatchLayer<f32, 4, 2, 3>>::batch_buffer();
+        batched.predict_batch(&i, &mut buffer)?;
+        batched.backpropagate_batch(&i, &buffer, &dy)?;
+
+        let replica = batched;
+        batched.add_gradients(&replica);
+        batched.add_gradients(&replica);
+        for (a, b) in layer
+            .weights
+            .gradient
+            .iter()
+            .zip(batched.weights.gradient.iter())
+        {
+            assert!((a - b).abs() < 1e-4, "Found {b}, expected {a}");
+        }
+
+        layer.apply_gradients();
+        for ((w, w0), g) in layer
+            .weights
+            .value
+            .iter()
+            .zip(weights.iter())
+            .zip(batched.weights.gradient.iter())
+        {
+            assert!((w - (w0 - 0.1 * g)).abs() < 1e-4);
+        }
+        assert_eq!(layer.weights.gradient, [0.; 8]);
+        assert_eq!(layer.biasies.gradient, [0.; 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dense_input_gradient() -> Result<()> {
+        fn check(layer: &mut impl Layer<f64, 4, 3, 3>) -> Result<()> {
+            let i = moo![f64: 1, -2, 0.5, 3];
+            let c = moo![f64: 1, -1, 2];
+            let mut o = [0f64; 3];
+
+            // Loss is the dot product of the output and c, so c is the gradient of the output.
+            let mut loss = |i: &[f64; 4]| -> Result<f64> {
+                layer.predict(i, &mut o)?;
+                Ok(o.iter().zip(c.iter()).map(|(o, c)| o * c).sum())
+            };
+
+            let h = 1e-5;
+            let mut numeric = [0f64; 4];
+            for n in 0..4 {
+                let (mut a, mut b) = (i, i);
+                a[n] += h;
+                b[n] -= h;
+                numeric[n] = (loss(&a)? - loss(&b)?) / (2. * h);
+            }
+
+            layer.predict(&i, &mut o)?;
+            let analytic = layer.backpropagate(&i, &o, c)?;
+
+            for n in 0..4 {
+                assert!(
+                    (numeric[n] - analytic.moo_ref()[n]).abs() < 1e-6,
+                    "Found {:?}, expected {numeric:?}",
+                    analytic.moo_ref()
+                );
+            }
+            Ok(())
+        }
+
+        check(&mut DenseLayer::<f64, Blas, 4, 3>::random(Sgd::new(1.)))?;
+        check(&mut DenseHeapLayer::<f64, Blas, 4, 3>::random(Sgd::new(1.)))
+    }
+
+    #[test]
+    fn softmax() -> Result<()> {
+        let mut softmax = Softmax::<f64, 3>::default();
+        let mut o = [0f64; 3];
+
+        softmax.predict(&[1000., 1000., 0.], &mut o)?;
+        assert_eq!(o, [0.5, 0.5, 0.]);
+
+        // Compare the Jacobian-vector product with finite differences of `c . softmax(i)`.
+        let i = moo![f64: 0.5, -1, 2];
+        let c = moo![f64: 1, -2, 0.5];
+        let mut loss = |i: &[f64; 3]| -> Result<f64> {
+            softmax.predict(i, &mut o)?;
+            Ok(o.iter().zip(c.iter()).map(|(o, c)| o * c).sum())
+        };
+
+        let h = 1e-5;
+        let mut numeric = [0f64; 3];
+        for n in 0..3 {
+            let (mut a, mut b) = (i, i);
+            a[n] += h;
+            b[n] -= h;
+            numeric[n] = (loss(&a)? - loss(&b)?) / (2. * h);
+        }
+
+        // The buffer still holds the output of the last perturbed input.
+        softmax.predict(&i, &mut o)?;
+        let analytic = softmax.backpropagate(&i, &o, c)?;
+        for n in 0..3 {
+            assert!(
+                (numeric[n] - analytic[n]).abs() < 1e-6,
+                "Found {analytic:?}, expected {numeric:?}"
+            );
+        }
+
+        // The fused loss on logits matches the cross-entropy of the softmax, with a gradient of `p - y`.
+        let y = onehot::<f64, 3>(1);
+        softmax.predict(&i, &mut o)?;
+        let (fused, gradient) = SoftmaxCrossEntropy.loss(&i, &y);
+        assert!((fused - CategoricalCrossEntropy.loss(&o, &y).0).abs() < 1e-12);
+        for n in 0..3 {
+            assert!((gradient[n] - (o[n] - y[n])).abs() < 1e-12);
+        }
+        assert_eq!(SoftmaxCrossEntropy.sparse(&i, 1), (fused, gradient));
+
+        Ok(())
+    }
+
+    #[test]
+    fn losses() {
+        fn check(loss: impl Loss<f64, 3>, o: [f64; 3], y: [f64; 3]) {
+            let (_, analytic) = loss.loss(&o, &y);
+            let h = 1e-6;
+            for n in 0..3 {
+                let (mut a, mut b) = (o, o);
+                a[n] += h;
+                b[n] -= h;
+                let numeric = (loss.loss(&a, &y).0 - loss.loss(&b, &y).0) / (2. * h);
+                assert!(
+                    (numeric - analytic[n]).abs() < 1e-5,
+                    "Found {analytic:?}, expected {numeric} at {n}"
+                );
+            }
+        }
+
+        let o = [0.2, 0.5, 0.3];
+        let y = [0.1, 0.8, 0.1];
+        check(Mse, o, y);
+        check(Mae, o, y);
+        check(Huber::new(0.2), o, y);
+        check(BinaryCrossEntropy, o, y);
+        check(CategoricalCrossEntropy, o, y);
+        check(SoftmaxCrossEntropy, o, y);
+        check(Hinge, o, [1., -1., 1.]);
+        check(KlDivergence, o, y);
+        check(CosineEmbedding::new(true), o, y);
+        check(CosineEmbedding::new(false), o, y);
+
+        let (loss, _) = Mse.loss(&o, &y);
+        assert!((loss - 0.14 / 3.).abs() < 1e-12);
+
+        let (dense, dense_gradient) = CategoricalCrossEntropy.loss(&o, &onehot::<f64, 3>(1));
+        let (sparse, sparse_gradient) = CategoricalCrossEntropy.sparse(&o, 1);
+        assert_eq!(dense, sparse);
+        assert_eq!(dense_gradient, sparse_gradient);
+    }
+
+    #[test]
+    fn gradcheck_layers() -> Result<()> {
+        let i = [0.5, -1.2, 2., 0.1];
+        let g = [1., -0.5, 2., 0.3];
+
+        fn check<
+            L: Layer<f64, 4, O, B> + Parameters<f64> + Clone,
+            const O: usize,
+            const B: usize,
+        >(
+            name: &str,
+            layer: L,
+            i: &[f64; 4],
+            g: &[f64; O],
+        ) -> Result<()> {
+            let check = gradcheck(&layer, i, g, 1e-5)?;
+            assert!(check.max_error < 1e-6, "{name}: {check:?}");
+            assert_eq!(check.param_errors.len(), L::PARAM_COUNT, "{name}");
+            assert!(
+                check.param_errors.iter().all(|&e| e < 1e-6),
+                "{name}: {check:?}"
+            );
+            Ok(())
+        }
+
+        check("Sigmoid", Sigmoid::<f64, 4>::default(), &i, &g)?;
+        check("Tanh", Tanh::<f64, 4>::default(), &i, &g)?;
+        check("Swish", Swish::<f64, 4>::default(), &i, &g)?;
+        check("Relu", Relu::<f64, 4>::default(), &i, &g)?;
+        check("None", None::<f64, 4>::default(), &i, &g)?;
+        check("Square", Square::<f64, 4>::default(), &i, &g)?;
+        check("Softmax", Softmax::<f64, 4>::default(), &i, &g)?;
+        check("LeakyRelu", LeakyRelu::<f64, 4>::new(0.2), &i, &g)?;
+        check("Elu", Elu::<f64, 4>::default(), &i, &g)?;
+        check("Selu", Selu::<f64, 4>::default(), &i, &g)?;
+        check("Gelu", Gelu::<f64, 4>::default(), &i, &g)?;
+        check("GeluTanh", GeluTanh::<f64, 4>::default(), &i, &g)?;
+        check("Softplus", Softplus::<f64, 4>::default(), &i, &g)?;
+        check("Mish", Mish::<f64, 4>::default(), &i, &g)?;
+        check("HardSigmoid", HardSigmoid::<f64, 4>::default(), &i, &g)?;
+        check("HardSwish", HardSwish::<f64, 4>::default(), &i, &g)?;
+        check("LogSoftmax", LogSoftmax::<f64, 4>::default(), &i, &g)?;
+        check("Softsign", Softsign::<f64, 4>::default(), &i, &g)?;
+        check(
+            "DenseLayer",
+            DenseLayer::<f64, Blas, 4, 3>::random(Sgd::new(0.1)),
+            &i,
+            &[1., -0.5, 2.],
+        )?;
+        check(
+            "DenseHeapLayer",
+            DenseHeapLayer::<f64, Blas, 4, 3>::random(Sgd::new(0.1)),
+            &i,
+            &[1., -0.5, 2.],
+        )?;
+        check("Prelu", Prelu::<f64, 4>::new(Sgd::new(0.1)), &i, &g)?;
+        check(
+            "Maxout",
+            Maxout::<f64, 4, 3, 3>::random(Sgd::new(0.1)),
+            &i,
+            &[1., -0.5, 2.],
+        )
+    }
+
+    #[test]
+    fn activation_with_dual_numbers() -> Result<()> {
+        use std::marker::PhantomData;
+
+        exotic::activation! {[f32, f64]: ApproxSigmoid = |x| x / (x + 1.)}
+        exotic::activation! {[f32, f64]: LogTanh = |x| (x.tanh() + 2.).ln()}
+        exotic::activation! {T: Cube = |x| x * x * x - T::_2}
+
+        let i = [0.5, 1.2, 2., 0.1];
+        let g = [1., -0.5, 2., 0.3];
+        for check in [
+            gradcheck(&ApproxSigmoid::<f64, 4>::default(), &i, &g, 1e-5)?,
+            gradcheck(&LogTanh::<f64, 4>::default(), &i, &g, 1e-5)?,
+            gradcheck(&Cube::<f64, 4>::default(), &i, &g, 1e-5)?,
+        ] {
+            assert!(check.max_error < 1e-6, "{check:?}");
+        }
+
+        let mut o = [0f32; 4];
+        let dx = Cube::<f32, 4>::default().backpropagate(&[1., 2., 3., 4.], &o, [1.; 4])?;
+        assert_eq!(dx, [3., 12., 27., 48.]);
+        Cube::<f32, 4>::default().predict(&[1., 2., 3., 4.], &mut o)?;
+        assert_eq!(o, [-1., 6., 25., 62.]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn activation_with_output_derivative() -> Result<()> {
+        use std::marker::PhantomData;
+
+        exotic::activation! {T: Exp = |x| x.exp_(), output |y| y}
+
+        let i = [0.5, -1.2, 2., 0.1];
+        let check = gradcheck(&Exp::<f64, 4>::default(), &i, &[1., -0.5, 2., 0.3], 1e-5)?;
+        assert!(check.max_error < 1e-6, "{check:?}");
+
+        // The derivative is computed from the cached output, and not from the input.
+        let dx = Exp::<f64, 4>::default().backpropagate(&i, &[1., 2., 3., 4.], [1.; 4])?;
+        assert_eq!(dx, [1., 2., 3., 4.]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parametric_activations() -> Result<()> {
+        let mut o = [0f32; 3];
+
+        LeakyRelu::<f32, 3>::new(0.5).predict(&[-2., 0., 2.], &mut o)?;
+        assert_eq!(o, [-1., 0., 2.]);
+        LeakyRelu::<f32, 3>::default().predict(&[-2., 0., 2.], &mut o)?;
+        assert_eq!(o, [-0.02, 0., 2.]);
+
+        Elu::<f32, 3>::new(2.).predict(&[-1000., 0., 2.], &mut o)?;
+        assert_eq!(o, [-2., 0., 2.]);
+
+        let mut o = [0f64; 3];
+        Gelu::<f64, 3>::default().predict(&[-1., 0., 1.], &mut o)?;
+        for (o, e) in o.iter().zip([-0.158655254, 0., 0.841344746]) {
+            assert!((o - e).abs() < 1e-6, "Found {o}, expected {e}");
+        }
+
+        LogSoftmax::<f64, 3>::default().predict(&[1000., 1000., 0.], &mut o)?;
+        assert!((o[0] - 0.5f64.ln()).abs() < 1e-12 && o[2] < -999.);
+
+        {
+            use std::marker::PhantomData;
+            exotic::activation! {T: Scaled { scale = 2.5 } = |x| x * scale}
+            Scaled::<f64, 3>::default().predict(&[-1., 0., 2.], &mut o)?;
+            assert_eq!(o, [-2.5, 0., 5.]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn learnable_activations() -> Result<()> {
+        let mut prelu = Prelu::<f64, 2>::new(Sgd::new(0.1));
+        let mut o = [0.; 2];
+        for _ in 0..200 {
+            let i = [-random::<f64>(), -random::<f64>()];
+            prelu.predict(&i, &mut o)?;
+            let (_, dy) = Mse.loss(&o, &[i[0] * 0.5, i[1] * 0.1]);
+            prelu.backpropagate(&i, &o, dy)?;
+        }
+        assert!(
+            (prelu.alpha.value[0] - 0.5).abs() < 1e-3 && (prelu.alpha.value[1] - 0.1).abs() < 1e-3
+        );
+
+        // Two pieces are enough for the absolute value,
+        // as long as each of them starts out being the largest for some inputs.
+        let mut maxout = Maxout::<f64, 1, 1, 2>::random(Sgd::new(0.1));
+        maxout.weights.value = [0.5, -0.1];
+        maxout.biasies.value = [0., 0.];
+        let mut o = [0.; 1];
+        let mut cost = 0.;
+        for _ in 0..2000 {
+            let i = [random::<f64>() * 2. - 1.];
+            maxout.predict(&i, &mut o)?;
+            let (c, dy) = Mse.loss(&o, &[i[0].abs()]);
+            cost = cost * 0.9 + c * 0.1;
+            maxout.backpropagate(&i, &o, dy)?;
+        }
+        assert!(cost < 1e-3, "{cost}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn schedulers() {
+        let mut step = StepDecay::new(1., 0.5, 2);
+        let lr: Vec<f32> = (0..5).map(|_| step.step()).collect();
+        assert_eq!(lr, [1., 1., 0.5, 0.5, 0.25]);
+
+        let mut cosine = CosineAnnealing::new(1., 0., 2, 2);
+        let lr: Vec<f64> = (0..7).map(|_| cosine.step()).collect();
+        assert_eq!(lr[0], 1.);
+        assert_eq!(lr[2], 1., "Expected a warm restart after 2 steps");
+        assert!((lr[4] - 0.5).abs() < 1e-9);
+        assert_eq!(lr[6], 1., "Expected the period to have doubled");
+
+        let mut warmup = LinearWarmup::new(Exponential::new(1., 1.), 4);
+        let lr: Vec<f32> = (0..5).map(|_| warmup.step()).collect();
+        assert_eq!(lr, [0.25, 0.5, 0.75, 1., 1.]);
+
+        // Starts at max_lr / div_factor, peaks at pct_start * total_steps and ends at initial / final_div_factor.
+        let mut one_cycle = OneCycle::new(1., 100);
+        let lr: Vec<f64> = (0..=100).map(|_| one_cycle.step()).collect();
+        assert!((lr[0] - 1. / 25.).abs() < 1e-12);
+        assert_eq!(lr[30], 1.);
+        assert!(lr.iter().all(|&lr| lr <= 1.));
+        assert!((lr[100] - 1. / 25. / 1e4).abs() < 1e-12);
+
+        let mut plateau = ReduceOnPlateau::new(1., 0.1, 2);
+        for metric in [3f32, 2., 2., 2.] {
+            plateau.observe(metric);
+        }
+        let lr: f32 = plateau.step();
+        assert_eq!(lr, 1.);
+        plateau.observe(2.);
+        let lr: f32 = plateau.step();
+        assert!((lr - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn cosine_annealing_without_period() {
+        CosineAnnealing::new(1., 0., 0, 2);
+    }
 }
@@ -5,19 +5,13 @@ use slas_backend::*;
 
 const TRN_IMAGES: usize = 60_000;
 
-model! {(
-    derive: [],
-    name: "Net",
-    layers: [
-        ("DenseLayer::<f32, Blas, {28*28}, 20>", "DenseLayer::random(0.01)"),
-        ("Tanh::<f32, 20>", "default()"),
-        ("DenseLayer::<f32, Blas, 20, 10>", "DenseLayer::random(0.01)"),
-        ("Softmax::<f32, 10>", "default()")
-    ],
-    float_type: "f32",
-    input_len: 784,
-    output_len: 10
-)}
+model! {
+    struct Net {
+        l0: DenseLayer<f32, Blas, { 28 * 28 }, 20> = DenseLayer::random(Sgd::new(0.01)),
+        l1: Tanh<f32, 20>,
+        l2: DenseLayer<f32, Blas, 20, 10> = DenseLayer::random(Sgd::new(0.01)),
+    }
+}
 
 fn argmax(slice: &[f32]) -> usize {
     let mut max = 0;
@@ -42,14 +36,16 @@ pub fn main() -> Result<()> {
     let trn_img = trn_img.iter().map(|n| *n as f32 / 255.).collect::<Vec<_>>();
 
     let mut net = Net::new();
-    let mut buffer = unsafe { Net::uninit_cache() };
+    let mut buffer = NetCache::new();
+
+    let mut scheduler = Exponential::new(0.01, 0.99999);
 
     let mut accuracy = [false; 400];
     let mut cost_sum = 0f32;
 
     for epoch in 0..400000 {
-        net.l0.lr *= 0.99999;
-        net.l2.lr *= 0.99999;
+        let lr = scheduler.step();
+        net.set_lr(lr);
 
         let idx = epoch % TRN_IMAGES;
 
@@ -61,16 +57,9 @@ pub fn main() -> Result<()> {
 
         net.predict(i, &mut buffer)?;
 
-        let y = onehot::<f32, 10>(trn_lbl[idx] as usize);
-        let o = &buffer[buffer.len() - 10..buffer.len()];
-        let dy = moo![|n| o[n] - y[n]; 10];
-
-        let cost = o
-            .iter()
-            .zip(y.iter())
-            .map(|(o, y)| (o - y).powi_(2))
-            .sum::<f32>()
-            .abs();
+        // The model outputs logits, which are turned into probabilities by the loss.
+        let o = buffer.output();
+        let (cost, dy) = SoftmaxCrossEntropy.sparse(o, trn_lbl[idx] as usize);
 
         if cost.is_nan() {
             panic!("cost is nan");
@@ -85,7 +74,7 @@ pub fn main() -> Result<()> {
                 "\raccuracy: {:.2}% lr: {:.5} cost: {:.4?}",
                 accuracy.iter().map(|n| *n as u8 as f32).sum::<f32>() / accuracy.len() as f32
                     * 100.,
-                net.l0.lr,
+                lr,
                 cost_sum / epoch as f32
             );
         }